@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+use url::{Host, Url};
+
+/// Settings under `allowlist` in signage.json: the hosts media may be downloaded from
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct AllowlistConfig {
+    /// Exact host names, or `*.example.com` for any subdomain of example.com (but not
+    /// example.com itself)
+    pub hosts: Vec<String>,
+    /// Reject plain `http` URLs
+    pub https_only: bool,
+}
+
+impl Default for AllowlistConfig {
+    fn default() -> Self {
+        AllowlistConfig {
+            hosts: vec![
+                "s3.amazonaws.com".to_string(),
+                "*.s3.amazonaws.com".to_string(),
+            ],
+            https_only: false,
+        }
+    }
+}
+
+impl AllowlistConfig {
+    /// Whether `host` is allowed; wildcards only match `domain` names, never IP addresses
+    fn host_allowed(&self, host: &str, domain: bool) -> bool {
+        self.hosts.iter().any(|pattern| {
+            let pattern = pattern.trim().trim_end_matches('.').to_ascii_lowercase();
+            match pattern.strip_prefix("*.") {
+                Some(_) if !domain => false,
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|prefix| prefix.len() > 1 && prefix.ends_with('.')),
+                None => host == pattern,
+            }
+        })
+    }
+
+    /// Checks the scheme and host of `url`, returning why it is rejected
+    pub fn check(&self, url: &str) -> Result<(), String> {
+        let parsed = Url::parse(url).map_err(|e| format!("invalid URL {:?}: {}", url, e))?;
+        match parsed.scheme() {
+            "https" => (),
+            "http" if !self.https_only => (),
+            scheme => return Err(format!("scheme {:?} is not allowed", scheme)),
+        }
+
+        // `Url` lowercases host names; a trailing dot names the same host
+        let domain = matches!(parsed.host(), Some(Host::Domain(_)));
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| format!("{:?} has no host", url))?
+            .trim_end_matches('.');
+        if !self.host_allowed(host, domain) {
+            return Err(format!("host {:?} is not in the allowlist", host));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist(hosts: &[&str], https_only: bool) -> AllowlistConfig {
+        AllowlistConfig {
+            hosts: hosts.iter().map(|host| host.to_string()).collect(),
+            https_only,
+        }
+    }
+
+    #[test]
+    fn urls_are_checked_by_host_and_scheme() {
+        let default = AllowlistConfig::default();
+        let ips = allowlist(&["127.0.0.1", "[::1]", "*.0.0.1"], false);
+        let https = allowlist(&["example.com"], true);
+        let cases = [
+            (&default, "https://s3.amazonaws.com/a.mp4", true),
+            (&default, "https://bucket.s3.amazonaws.com/a.mp4", true),
+            (&default, "https://a.b.s3.amazonaws.com/a.mp4", true),
+            (&default, "HTTPS://Bucket.S3.AmazonAWS.com/a.mp4", true),
+            (&default, "https://bucket.s3.amazonaws.com./a.mp4", true),
+            (&default, "https://evil.com/?x=s3.amazonaws.com", false),
+            (&default, "https://evil.com/s3.amazonaws.com", false),
+            (&default, "https://evils3.amazonaws.com/a.mp4", false),
+            (&default, "https://s3.amazonaws.com.evil.com/a.mp4", false),
+            (&default, "https://s3.amazonaws.com@evil.com/a.mp4", false),
+            (&default, "https://evil.com@s3.amazonaws.com/a.mp4", true),
+            (&default, "ftp://s3.amazonaws.com/a.mp4", false),
+            (&default, "not a url", false),
+            (&ips, "http://127.0.0.1/a.mp4", true),
+            (&ips, "http://2130706433/a.mp4", true),
+            (&ips, "http://[::1]/a.mp4", true),
+            (&ips, "http://10.0.0.1/a.mp4", false),
+            (&https, "https://example.com/a.mp4", true),
+            (&https, "http://example.com/a.mp4", false),
+        ];
+        for (allowlist, url, allowed) in cases {
+            assert_eq!(allowlist.check(url).is_ok(), allowed, "{}", url);
+        }
+    }
+
+    #[test]
+    fn wildcards_match_subdomains_only() {
+        let wildcard = allowlist(&["*.example.com"], false);
+        assert!(wildcard.check("https://cdn.example.com/").is_ok());
+        assert!(wildcard.check("https://example.com/").is_err());
+        assert!(wildcard.check("https://badexample.com/").is_err());
+
+        // Patterns are compared case-insensitively and without a trailing dot
+        let mixed = allowlist(&["*.Example.COM.", "Media.Example.org"], false);
+        assert!(mixed.check("https://cdn.example.com/").is_ok());
+        assert!(mixed.check("https://media.example.org/").is_ok());
+    }
+}
@@ -0,0 +1,385 @@
+use crate::commands::CommandReport;
+use crate::config::Config;
+use crate::content::{ContentError, Readiness};
+use crate::display::DisplayState;
+use crate::proof_of_play::PlayRecord;
+use crate::recurrence::Recurrence;
+use crate::retry::{retry_after, RetryPolicy, Retryable};
+use crate::telemetry::{count, COUNTERS};
+use crate::util::{ClientTimelineScheduleResponse, Video};
+use crate::watchdog::PlayerIncident;
+use chrono::{DateTime, Utc};
+use reqwest::header::HeaderValue;
+use reqwest::multipart::{Form, Part};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fmt;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClientActions {
+    pub client_id: Uuid,
+    pub restart_app: bool,
+    pub restart: bool,
+    pub screenshot: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientPlaylistSchedule {
+    pub id: Uuid,
+    pub playlist_id: Uuid,
+    pub device_id: Uuid,
+    pub organization_id: Uuid,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub playlist_name: String,
+    /// Higher wins when windows overlap; older backends don't send it
+    #[serde(default)]
+    pub priority: i32,
+    /// Repeats a daypart within `start_time`..`end_time` instead of playing the whole range
+    #[serde(default)]
+    pub recurrence: Option<Recurrence>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// Errors returned by `SignageApi`
+#[derive(Debug)]
+pub enum ApiError {
+    /// The configured API key cannot be sent as a header value
+    InvalidApiKey,
+    /// The request could not be built, so sending it again would fail the same way
+    Build(reqwest::Error),
+    /// The request never got a response (DNS, connect, timeout, ...)
+    Network(reqwest::Error),
+    /// The backend rejected our API key (401/403)
+    Auth(StatusCode),
+    /// The endpoint or device does not exist (404)
+    NotFound(String),
+    /// The backend asked us to slow down (429), optionally saying for how long
+    RateLimited(Option<Duration>),
+    /// The backend failed to handle the request (5xx)
+    Server(StatusCode, Option<Duration>),
+    /// Any other non-success status
+    Status(StatusCode),
+    /// The response body did not match the expected type
+    Decode(reqwest::Error),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::InvalidApiKey => write!(f, "API key is not a valid header value"),
+            ApiError::Build(e) => write!(f, "failed to build request: {}", e),
+            ApiError::Network(e) => write!(f, "network error: {}", e),
+            ApiError::Auth(status) => write!(f, "authentication failed: {}", status),
+            ApiError::NotFound(url) => write!(f, "not found: {}", url),
+            ApiError::RateLimited(_) => write!(f, "rate limited"),
+            ApiError::Server(status, _) => write!(f, "server error: {}", status),
+            ApiError::Status(status) => write!(f, "unexpected status: {}", status),
+            ApiError::Decode(e) => write!(f, "failed to decode response: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ApiError::Build(e) | ApiError::Network(e) | ApiError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl Retryable for ApiError {
+    fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ApiError::Network(_) | ApiError::RateLimited(_) | ApiError::Server(..)
+        )
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ApiError::RateLimited(delay) | ApiError::Server(_, delay) => *delay,
+            _ => None,
+        }
+    }
+}
+
+/// Typed client for the signage backend, one method per endpoint
+#[derive(Clone)]
+pub struct SignageApi {
+    client: Client,
+    url: String,
+    id: String,
+    key: String,
+    retry: RetryPolicy,
+    metrics_timeout: Duration,
+}
+
+impl SignageApi {
+    pub fn new(client: Client, config: &Config) -> Self {
+        SignageApi {
+            client,
+            url: config.url.trim_end_matches('/').to_string(),
+            id: config.id.clone(),
+            key: config.key.clone().unwrap_or_default(),
+            retry: config.retry.clone(),
+            metrics_timeout: Duration::from_secs(config.metrics.timeout_secs),
+        }
+    }
+
+    /// Builds `{url}/{endpoint}/{id}` for the endpoints keyed by device id
+    fn device_url(&self, endpoint: &str) -> String {
+        format!("{}/{}/{}", self.url, endpoint, self.id)
+    }
+
+    fn api_key(&self) -> Result<HeaderValue, ApiError> {
+        HeaderValue::from_str(&self.key).map_err(|_| ApiError::InvalidApiKey)
+    }
+
+    fn get(&self, url: &str) -> Result<RequestBuilder, ApiError> {
+        Ok(self.client.get(url).header("APIKEY", self.api_key()?))
+    }
+
+    fn post(&self, url: &str) -> Result<RequestBuilder, ApiError> {
+        Ok(self.client.post(url).header("APIKEY", self.api_key()?))
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    /// Sends `request` once and maps the response status onto `ApiError`
+    async fn send_once(request: RequestBuilder) -> Result<Response, ApiError> {
+        let result = Self::check(request.send().await);
+        count(match result {
+            Ok(_) => &COUNTERS.api_success,
+            Err(_) => &COUNTERS.api_failure,
+        });
+        result
+    }
+
+    fn check(response: reqwest::Result<Response>) -> Result<Response, ApiError> {
+        let response = response.map_err(ApiError::Network)?;
+        let status = response.status();
+
+        match status {
+            s if s.is_success() => Ok(response),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(ApiError::Auth(status)),
+            StatusCode::NOT_FOUND => Err(ApiError::NotFound(response.url().to_string())),
+            StatusCode::TOO_MANY_REQUESTS => {
+                Err(ApiError::RateLimited(retry_after(response.headers())))
+            }
+            s if s.is_server_error() => {
+                Err(ApiError::Server(status, retry_after(response.headers())))
+            }
+            _ => Err(ApiError::Status(status)),
+        }
+    }
+
+    /// Sends the request produced by `build` under the retry policy.
+    /// `build` runs once per attempt since request bodies cannot always be cloned.
+    async fn send<F>(&self, build: F) -> Result<Response, ApiError>
+    where
+        F: Fn() -> Result<RequestBuilder, ApiError>,
+    {
+        self.retry
+            .run(|| async { Self::send_once(build()?).await })
+            .await
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, url: &str) -> Result<T, ApiError> {
+        self.retry
+            .run(|| async {
+                Self::send_once(self.get(url)?)
+                    .await?
+                    .json::<T>()
+                    .await
+                    .map_err(ApiError::Decode)
+            })
+            .await
+    }
+
+    async fn post_json(&self, url: &str, body: &serde_json::Value) -> Result<(), ApiError> {
+        self.send(|| Ok(self.post(url)?.json(body)))
+            .await
+            .map(|_| ())
+    }
+
+    /// GET /health, without retries
+    pub async fn health(&self) -> Result<(), ApiError> {
+        Self::send_once(self.client.get(format!("{}/health", self.url)))
+            .await
+            .map(|_| ())
+    }
+
+    /// GET /client-actions/{id}
+    pub async fn client_actions(&self) -> Result<ClientActions, ApiError> {
+        self.get_json(&self.device_url("client-actions")).await
+    }
+
+    /// GET /client-commands/{id}, the commands still waiting to run, left unparsed so one
+    /// unknown command doesn't hide the rest
+    pub async fn commands(&self) -> Result<Vec<serde_json::Value>, ApiError> {
+        self.get_json(&self.device_url("client-commands")).await
+    }
+
+    /// POST /client-commands/{id}/{command_id}/status
+    pub async fn report_command(
+        &self,
+        command_id: Uuid,
+        report: &CommandReport,
+    ) -> Result<(), ApiError> {
+        let url = format!(
+            "{}/{}/status",
+            self.device_url("client-commands"),
+            command_id
+        );
+        self.send(|| Ok(self.post(&url)?.json(report)))
+            .await
+            .map(|_| ())
+    }
+
+    /// GET /client-playlists_schedule/{id}
+    pub async fn playlist_schedule(&self) -> Result<Vec<ClientPlaylistSchedule>, ApiError> {
+        self.get_json(&self.device_url("client-playlists_schedule"))
+            .await
+    }
+
+    /// GET /client-timeline-schedule/{id}, the active/next playlists and pending update flags
+    pub async fn timeline(&self) -> Result<ClientTimelineScheduleResponse, ApiError> {
+        self.get_json(&self.device_url("client-timeline-schedule"))
+            .await
+    }
+
+    /// GET /client-playlist-assets/{id}/{playlist_id}, the media in a playlist
+    pub async fn playlist_assets(&self, playlist_id: Uuid) -> Result<Vec<Video>, ApiError> {
+        let url = format!(
+            "{}/{}",
+            self.device_url("client-playlist-assets"),
+            playlist_id
+        );
+        self.get_json(&url).await
+    }
+
+    /// POST /client-playlist-readiness/{id}, how much of an upcoming playlist is on disk
+    pub async fn report_readiness(&self, readiness: &Readiness) -> Result<(), ApiError> {
+        let url = self.device_url("client-playlist-readiness");
+        self.send(|| Ok(self.post(&url)?.json(readiness)))
+            .await
+            .map(|_| ())
+    }
+
+    /// POST /client-content-errors/{id}, an asset that could not be used
+    pub async fn report_content_error(&self, error: &ContentError) -> Result<(), ApiError> {
+        let url = self.device_url("client-content-errors");
+        self.send(|| Ok(self.post(&url)?.json(error)))
+            .await
+            .map(|_| ())
+    }
+
+    /// POST /client-player-incidents/{id}, a player failure and what was done about it
+    pub async fn report_player_incident(&self, incident: &PlayerIncident) -> Result<(), ApiError> {
+        let url = self.device_url("client-player-incidents");
+        self.send(|| Ok(self.post(&url)?.json(incident)))
+            .await
+            .map(|_| ())
+    }
+
+    /// POST /client-display/{id}, the layout and rotation now in effect
+    pub async fn report_display(&self, state: &DisplayState) -> Result<(), ApiError> {
+        let url = self.device_url("client-display");
+        self.send(|| Ok(self.post(&url)?.json(state)))
+            .await
+            .map(|_| ())
+    }
+
+    /// POST /update-client-playlist/{id}, `fallback` being true when no schedule is active
+    pub async fn update_playlist_id(
+        &self,
+        playlist_id: Uuid,
+        fallback: bool,
+    ) -> Result<(), ApiError> {
+        self.post_json(
+            &self.device_url("update-client-playlist"),
+            &json!({ "playlist_id": playlist_id, "fallback": fallback }),
+        )
+        .await
+    }
+
+    /// POST /update-restart-app-device/{id}, clearing the restart app flag
+    pub async fn update_restart_app_flag(&self) -> Result<(), ApiError> {
+        self.post_json(
+            &self.device_url("update-restart-app-device"),
+            &json!({ "restart_app": false }),
+        )
+        .await
+    }
+
+    /// POST /update-restart-device/{id}, clearing the restart flag
+    pub async fn update_restart_flag(&self) -> Result<(), ApiError> {
+        self.post_json(
+            &self.device_url("update-restart-device"),
+            &json!({ "restart": false }),
+        )
+        .await
+    }
+
+    /// POST /update-screenshot-device/{id}, clearing the screenshot flag
+    pub async fn update_screenshot_flag(&self) -> Result<(), ApiError> {
+        self.post_json(
+            &self.device_url("update-screenshot-device"),
+            &json!({ "screenshot": false }),
+        )
+        .await
+    }
+
+    /// POST /upload-screenshot/{id} with `png` as a multipart file
+    pub async fn upload_screenshot(&self, png: Vec<u8>) -> Result<(), ApiError> {
+        let url = self.device_url("upload-screenshot");
+
+        self.send(|| {
+            let part = Part::bytes(png.clone())
+                .file_name("screenshot.png")
+                .mime_str("image/png")
+                .map_err(ApiError::Build)?;
+            Ok(self.post(&url)?.multipart(Form::new().part("file", part)))
+        })
+        .await
+        .map(|_| ())
+    }
+
+    /// POST /client_vitals/{id}
+    pub async fn send_metrics<T: Serialize>(&self, metrics: &T) -> Result<(), ApiError> {
+        let url = self.device_url("client_vitals");
+
+        self.send(|| Ok(self.post(&url)?.timeout(self.metrics_timeout).json(metrics)))
+            .await
+            .map(|_| ())
+    }
+
+    /// POST /client_vitals_batch/{id} with samples that could not be delivered when taken
+    pub async fn send_metrics_batch<T: Serialize>(&self, samples: &[T]) -> Result<(), ApiError> {
+        let url = self.device_url("client_vitals_batch");
+        let body = json!({ "samples": samples });
+
+        self.send(|| Ok(self.post(&url)?.timeout(self.metrics_timeout).json(&body)))
+            .await
+            .map(|_| ())
+    }
+
+    /// POST /client-proof-of-play/{id} with a batch of plays; the backend drops ids it has seen
+    pub async fn send_proof_of_play(&self, records: &[PlayRecord]) -> Result<(), ApiError> {
+        let url = self.device_url("client-proof-of-play");
+        let body = json!({ "records": records });
+
+        self.send(|| Ok(self.post(&url)?.json(&body)))
+            .await
+            .map(|_| ())
+    }
+}
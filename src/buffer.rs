@@ -0,0 +1,233 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{boxed::Box, error::Error};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+/// `DiskQueue::len` before the file has been counted
+const UNKNOWN: usize = usize::MAX;
+
+/// Records taken from the front of a `DiskQueue` by `peek`
+pub struct Batch<T> {
+    pub records: Vec<T>,
+    /// Lines covered, including unreadable ones that were skipped
+    lines: Vec<String>,
+}
+
+impl<T> Batch<T> {
+    /// True when there was nothing left in the queue
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
+
+/// A bounded FIFO of JSON records stored one per line on disk.
+///
+/// Records are appended to the file and synced before `push` returns. When either bound is
+/// exceeded the oldest records are dropped, a tenth more than needed so this doesn't happen on
+/// every push, and the file always holds the most recent history. Dropping records rewrites
+/// the file through a synced temporary file and a rename, which keeps the queue intact across
+/// power cuts.
+pub struct DiskQueue<T> {
+    path: PathBuf,
+    max_entries: usize,
+    max_bytes: u64,
+    /// Lines in the file once known, so a push doesn't have to count them
+    entries: AtomicUsize,
+    _record: PhantomData<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> DiskQueue<T> {
+    pub fn new(path: impl Into<PathBuf>, max_entries: usize, max_bytes: u64) -> Self {
+        DiskQueue {
+            path: path.into(),
+            max_entries,
+            max_bytes,
+            entries: AtomicUsize::new(UNKNOWN),
+            _record: PhantomData,
+        }
+    }
+
+    /// Raw lines currently in the queue, oldest first
+    async fn lines(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        match fs::read_to_string(&self.path).await {
+            Ok(contents) => Ok(contents
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(str::to_string)
+                .collect()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replaces the file with `lines`, less the oldest ones if they exceed a bound
+    async fn store(&self, lines: &[String]) -> Result<(), Box<dyn Error>> {
+        let over = |entries: usize, bytes: u64| {
+            entries > self.max_entries || (bytes > self.max_bytes && entries > 1)
+        };
+        let mut bytes: u64 = lines.iter().map(|line| line.len() as u64 + 1).sum();
+        let mut evict = 0;
+        if over(lines.len(), bytes) {
+            // Down to nine tenths of the bounds
+            let (max_entries, max_bytes) = (self.max_entries, self.max_bytes);
+            while lines.len() - evict > 1
+                && (lines.len() - evict > max_entries - max_entries / 10
+                    || bytes > max_bytes - max_bytes / 10)
+            {
+                bytes -= lines[evict].len() as u64 + 1;
+                evict += 1;
+            }
+            eprintln!("Dropping {} buffered record(s) from {:?}", evict, self.path);
+        }
+        let lines = &lines[evict..];
+
+        let mut contents = lines.join("\n");
+        if !contents.is_empty() {
+            contents.push('\n');
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp).await?;
+        file.write_all(contents.as_bytes()).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp, &self.path).await?;
+        self.entries.store(lines.len(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Appends `record`, evicting the oldest records once a bound is exceeded
+    pub async fn push(&self, record: &T) -> Result<(), Box<dyn Error>> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        file.sync_data().await?;
+        let bytes = file.metadata().await?.len();
+
+        let entries = match self.entries.load(Ordering::Relaxed) {
+            UNKNOWN => self.lines().await?.len(),
+            entries => entries + 1,
+        };
+        self.entries.store(entries, Ordering::Relaxed);
+
+        if entries > self.max_entries || (bytes > self.max_bytes && entries > 1) {
+            let lines = self.lines().await?;
+            self.store(&lines).await?;
+        }
+        Ok(())
+    }
+
+    /// Up to `count` of the oldest lines, skipping any that no longer parse
+    pub async fn peek(&self, count: usize) -> Result<Batch<T>, Box<dyn Error>> {
+        let mut lines = self.lines().await?;
+        lines.truncate(count);
+
+        Ok(Batch {
+            records: lines
+                .iter()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect(),
+            lines,
+        })
+    }
+
+    /// Removes the lines covered by `batch`, once its records were delivered. Records pushed
+    /// since the `peek` may have evicted some of them already.
+    pub async fn remove(&self, batch: &Batch<T>) -> Result<(), Box<dyn Error>> {
+        let lines = self.lines().await?;
+        let evicted = (0..=batch.lines.len())
+            .find(|evicted| lines.starts_with(&batch.lines[*evicted..]))
+            .unwrap_or(batch.lines.len());
+        self.store(&lines[batch.lines.len() - evicted..]).await
+    }
+
+    pub async fn len(&self) -> Result<usize, Box<dyn Error>> {
+        Ok(self.lines().await?.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(dir: &tempfile::TempDir, max_entries: usize) -> DiskQueue<u32> {
+        DiskQueue::new(dir.path().join("queue.jsonl"), max_entries, 1024 * 1024)
+    }
+
+    async fn contents(queue: &DiskQueue<u32>) -> Vec<u32> {
+        queue.peek(usize::MAX).await.unwrap().records
+    }
+
+    #[tokio::test]
+    async fn pushes_append_and_peek_takes_the_oldest() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = queue(&dir, 100);
+        for record in 0..5 {
+            queue.push(&record).await.unwrap();
+        }
+        assert_eq!(queue.peek(2).await.unwrap().records, vec![0, 1]);
+
+        let batch = queue.peek(2).await.unwrap();
+        queue.remove(&batch).await.unwrap();
+        assert_eq!(contents(&queue).await, vec![2, 3, 4]);
+        assert!(!dir.path().join("queue.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn a_full_queue_drops_the_oldest_tenth() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = queue(&dir, 20);
+        for record in 0..21 {
+            queue.push(&record).await.unwrap();
+        }
+        assert_eq!(contents(&queue).await, (3..21).collect::<Vec<_>>());
+
+        // Counted from the file by a queue that didn't write it
+        let reopened = DiskQueue::<u32>::new(dir.path().join("queue.jsonl"), 20, 1024 * 1024);
+        reopened.push(&21).await.unwrap();
+        assert_eq!(reopened.len().await.unwrap(), 19);
+    }
+
+    #[tokio::test]
+    async fn remove_only_drops_what_is_left_of_the_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = queue(&dir, 10);
+        for record in 0..10 {
+            queue.push(&record).await.unwrap();
+        }
+        let batch = queue.peek(4).await.unwrap();
+
+        // Pushed while the batch was uploading, evicting 0 and 1 of it
+        queue.push(&10).await.unwrap();
+        assert_eq!(contents(&queue).await, (2..11).collect::<Vec<_>>());
+
+        queue.remove(&batch).await.unwrap();
+        assert_eq!(contents(&queue).await, (4..11).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn unreadable_lines_are_skipped_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = queue(&dir, 10);
+        fs::write(dir.path().join("queue.jsonl"), "1\n{garbled\n2\n")
+            .await
+            .unwrap();
+
+        let batch = queue.peek(10).await.unwrap();
+        assert_eq!(batch.records, vec![1, 2]);
+        queue.remove(&batch).await.unwrap();
+        assert!(queue.peek(10).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,438 @@
+use crate::sysinfo::disk_usage;
+use crate::util::{media_files, sha256_file, Video};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::{boxed::Box, error::Error};
+use tokio::fs;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// Settings under `cache` in signage.json
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Most bytes of media kept on disk; unlimited when unset
+    pub max_bytes: Option<u64>,
+    /// Free space to leave on the filesystem holding the media
+    pub min_free_bytes: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            max_bytes: None,
+            min_free_bytes: 512 * 1024 * 1024,
+        }
+    }
+}
+
+/// What the cache knows about one media file
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Entry {
+    asset_id: String,
+    size: u64,
+    last_used: DateTime<Utc>,
+    /// Playlists the asset has been synced or preloaded for
+    playlists: BTreeSet<Uuid>,
+    /// Checksum the backend gave for the asset, if any
+    #[serde(default)]
+    sha256: Option<String>,
+}
+
+impl Entry {
+    /// Why the file at `path` no longer matches what was recorded, if it doesn't
+    async fn mismatch(&self, path: &Path, length: u64) -> Result<Option<String>, Box<dyn Error>> {
+        if length != self.size {
+            return Ok(Some(format!(
+                "expected {} bytes, found {}",
+                self.size, length
+            )));
+        }
+        if let Some(expected) = &self.sha256 {
+            let actual = sha256_file(&path.to_string_lossy()).await?;
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Ok(Some(format!(
+                    "expected SHA-256 {}, found {}",
+                    expected, actual
+                )));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Assets that must not be evicted
+#[derive(Debug, Default, Clone)]
+struct Pins {
+    current: HashSet<String>,
+    upcoming: HashSet<String>,
+    fallback: HashSet<String>,
+}
+
+impl Pins {
+    fn contains(&self, asset_id: &str) -> bool {
+        self.current.contains(asset_id)
+            || self.upcoming.contains(asset_id)
+            || self.fallback.contains(asset_id)
+    }
+}
+
+fn asset_ids(videos: &[Video]) -> HashSet<String> {
+    videos.iter().map(|video| video.id.clone()).collect()
+}
+
+/// Room set aside for a download that isn't in the index yet
+#[derive(Debug, Clone, Copy)]
+struct Reserved {
+    /// Size the file will have, counted against the quota
+    size: u64,
+    /// Bytes still to be written, counted against free space
+    to_write: u64,
+}
+
+#[derive(Default)]
+struct State {
+    /// Keyed by file name within the data directory
+    entries: BTreeMap<String, Entry>,
+    pins: Pins,
+    /// Keyed by asset id
+    reserved: HashMap<String, Reserved>,
+}
+
+/// Tracks the media downloaded to `dir` in `media_index.json` there and keeps it within the configured quota and
+/// free space by evicting the least recently used files that no pinned playlist needs.
+///
+/// Only files recorded in the index are ever evicted. Room promised to downloads still in
+/// progress counts as used, so parallel downloads can't overfill the disk between them.
+pub struct MediaCache {
+    dir: PathBuf,
+    config: CacheConfig,
+    state: Mutex<State>,
+}
+
+fn file_name(path: &str) -> Option<String> {
+    Some(Path::new(path).file_name()?.to_str()?.to_string())
+}
+
+impl MediaCache {
+    pub fn new(dir: impl Into<PathBuf>, config: CacheConfig) -> Self {
+        MediaCache {
+            dir: dir.into(),
+            config,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Loads the index and brings it in line with the media on disk, forgetting files that are
+    /// gone, deleting ones that no longer match their recorded size or checksum and adding ones
+    /// it doesn't know, e.g. after losing media_index.json. The media of the current playlist's
+    /// `videos` is pinned.
+    pub async fn open(&self, videos: &[Video]) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.lock().await;
+        state.pins.current = asset_ids(videos);
+        if let Ok(contents) = fs::read(self.dir.join("media_index.json")).await {
+            match serde_json::from_slice(&contents) {
+                Ok(entries) => state.entries = entries,
+                Err(e) => eprintln!("Ignoring unreadable media index: {}", e),
+            }
+        }
+
+        // Forget files that have gone missing or are corrupt, so they are fetched again
+        let mut stale = Vec::new();
+        for (name, entry) in &state.entries {
+            let path = self.dir.join(name);
+            let Ok(metadata) = fs::metadata(&path).await else {
+                stale.push(name.clone());
+                continue;
+            };
+            let mismatch = entry.mismatch(&path, metadata.len()).await?;
+            if let Some(e) = mismatch {
+                eprintln!("Removing corrupt asset {}: {}", path.display(), e);
+                fs::remove_file(&path).await?;
+                stale.push(name.clone());
+            }
+        }
+        for name in stale {
+            state.entries.remove(&name);
+        }
+
+        // Media is stored as `{asset_id}.{ext}`; its age is taken from the file
+        let files = media_files(&self.dir).await?;
+        for name in files {
+            if state.entries.contains_key(&name) {
+                continue;
+            }
+            let Ok(metadata) = fs::metadata(self.dir.join(&name)).await else {
+                continue;
+            };
+            let Some(asset_id) = Path::new(&name).file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            println!("Adding {} to the media index", name);
+            state.entries.insert(
+                name.clone(),
+                Entry {
+                    asset_id: asset_id.to_string(),
+                    size: metadata.len(),
+                    last_used: metadata
+                        .modified()
+                        .map_or_else(|_| Utc::now(), DateTime::from),
+                    playlists: BTreeSet::new(),
+                    sha256: None,
+                },
+            );
+        }
+
+        self.save(&state).await
+    }
+
+    async fn save(&self, state: &State) -> Result<(), Box<dyn Error>> {
+        let path = self.dir.join("media_index.json");
+        let tmp = self.dir.join("media_index.json.tmp");
+        fs::write(&tmp, serde_json::to_vec(&state.entries)?).await?;
+        fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+
+    /// Protects the media of the playlist now playing from eviction
+    pub async fn pin_current(&self, videos: &[Video]) {
+        self.state.lock().await.pins.current = asset_ids(videos);
+    }
+
+    /// Protects the media of the playlist being preloaded from eviction
+    pub async fn pin_upcoming(&self, videos: &[Video]) {
+        self.state.lock().await.pins.upcoming = asset_ids(videos);
+    }
+
+    /// Protects the media of the fallback playlist from eviction
+    pub async fn pin_fallback(&self, videos: &[Video]) {
+        self.state.lock().await.pins.fallback = asset_ids(videos);
+    }
+
+    /// Records that `video`, stored at `path`, is on disk for `playlist_id`, marking it used
+    pub async fn record(
+        &self,
+        playlist_id: Uuid,
+        video: &Video,
+        path: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        let Some(name) = file_name(path) else {
+            return Ok(());
+        };
+        let size = fs::metadata(path).await?.len();
+
+        let mut state = self.state.lock().await;
+        state.reserved.remove(&video.id);
+        let entry = state.entries.entry(name).or_insert_with(|| Entry {
+            asset_id: video.id.clone(),
+            size,
+            last_used: Utc::now(),
+            playlists: BTreeSet::new(),
+            sha256: None,
+        });
+        entry.size = size;
+        entry.sha256.clone_from(&video.sha256);
+        entry.last_used = Utc::now();
+        entry.playlists.insert(playlist_id);
+        self.save(&state).await
+    }
+
+    /// Reserves room for a download of `asset_id` that will be `size` bytes once finished, of
+    /// which `to_write` are still to come, replacing any earlier reservation for it. Least
+    /// recently used unpinned files are evicted as needed. Fails without evicting anything when
+    /// the file cannot fit even then.
+    pub async fn reserve(
+        &self,
+        asset_id: &str,
+        size: u64,
+        to_write: u64,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state.lock().await;
+
+        let cached: u64 = state.entries.values().map(|entry| entry.size).sum();
+        let (reserved_size, reserved_write) = state
+            .reserved
+            .iter()
+            .filter(|(id, _)| id.as_str() != asset_id)
+            .fold((0, 0), |(size, to_write), (_, reserved)| {
+                (size + reserved.size, to_write + reserved.to_write)
+            });
+        let free =
+            disk_usage(&self.dir).map(|usage| usage.total_bytes.saturating_sub(usage.used_bytes));
+
+        let over_quota = self
+            .config
+            .max_bytes
+            .map_or(0, |max| (cached + reserved_size + size).saturating_sub(max));
+        let short_of_free = free.map_or(0, |free| {
+            (self.config.min_free_bytes + reserved_write + to_write).saturating_sub(free)
+        });
+        let mut needed = over_quota.max(short_of_free);
+        if needed == 0 {
+            state
+                .reserved
+                .insert(asset_id.to_string(), Reserved { size, to_write });
+            return Ok(());
+        }
+
+        let mut candidates: Vec<(String, Entry)> = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| !state.pins.contains(&entry.asset_id))
+            .map(|(name, entry)| (name.clone(), entry.clone()))
+            .collect();
+        candidates.sort_by_key(|(_, entry)| entry.last_used);
+
+        let evictable: u64 = candidates.iter().map(|(_, entry)| entry.size).sum();
+        if evictable < needed {
+            return Err(format!(
+                "{} bytes do not fit: {} more would have to be freed but only {} can be evicted",
+                size, needed, evictable
+            )
+            .into());
+        }
+
+        for (name, entry) in candidates {
+            if needed == 0 {
+                break;
+            }
+            println!(
+                "Evicting {} ({} bytes, last used {})",
+                name, entry.size, entry.last_used
+            );
+            match fs::remove_file(self.dir.join(&name)).await {
+                Ok(()) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+            state.entries.remove(&name);
+            needed = needed.saturating_sub(entry.size);
+        }
+        state
+            .reserved
+            .insert(asset_id.to_string(), Reserved { size, to_write });
+        self.save(&state).await
+    }
+
+    /// Gives back the room reserved for `asset_id`, once its download has ended either way
+    pub async fn release(&self, asset_id: &str) {
+        self.state.lock().await.reserved.remove(asset_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(id: &str) -> Video {
+        Video {
+            id: id.to_string(),
+            asset_url: format!("https://example.com/{}.mp4", id),
+            asset_order: 0,
+            asset_name: String::new(),
+            size: None,
+            sha256: None,
+        }
+    }
+
+    /// A cache of at most `max_bytes` in `dir`, ignoring free space
+    fn cache(dir: &tempfile::TempDir, max_bytes: u64) -> MediaCache {
+        MediaCache::new(
+            dir.path(),
+            CacheConfig {
+                max_bytes: Some(max_bytes),
+                min_free_bytes: 0,
+            },
+        )
+    }
+
+    /// Writes a 10 byte asset `id` and records it, in that order of use
+    async fn download(cache: &MediaCache, dir: &tempfile::TempDir, id: &str) {
+        let path = dir.path().join(format!("{}.mp4", id));
+        fs::write(&path, [0u8; 10]).await.unwrap();
+        let path = path.to_str().unwrap();
+        cache.record(Uuid::nil(), &video(id), path).await.unwrap();
+    }
+
+    fn exists(dir: &tempfile::TempDir, id: &str) -> bool {
+        dir.path().join(format!("{}.mp4", id)).exists()
+    }
+
+    #[tokio::test]
+    async fn the_least_recently_used_media_is_evicted_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir, 30);
+        for id in ["a", "b", "c", "a"] {
+            download(&cache, &dir, id).await;
+        }
+
+        cache.reserve("d", 10, 10).await.unwrap();
+        assert!(!exists(&dir, "b"));
+        assert!(exists(&dir, "a") && exists(&dir, "c"));
+    }
+
+    #[tokio::test]
+    async fn pinned_media_is_never_evicted() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir, 30);
+        for id in ["a", "b", "c"] {
+            download(&cache, &dir, id).await;
+        }
+        cache.pin_current(&[video("a")]).await;
+        cache.pin_upcoming(&[video("b")]).await;
+        cache.pin_fallback(&[video("c")]).await;
+
+        assert!(cache.reserve("d", 10, 10).await.is_err());
+        assert!(exists(&dir, "a") && exists(&dir, "b") && exists(&dir, "c"));
+    }
+
+    #[tokio::test]
+    async fn reservations_count_against_the_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir, 30);
+
+        cache.reserve("a", 20, 20).await.unwrap();
+        assert!(cache.reserve("b", 20, 20).await.is_err());
+        // Reserving again for the same asset replaces its reservation
+        cache.reserve("a", 25, 25).await.unwrap();
+
+        cache.release("a").await;
+        cache.reserve("b", 20, 20).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn opening_adopts_unknown_files_and_keeps_reservations() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir, 30);
+        cache.reserve("a", 20, 20).await.unwrap();
+        fs::write(dir.path().join("u.mp4"), [0u8; 5]).await.unwrap();
+        fs::write(dir.path().join("a.mp4.part"), [0u8; 5])
+            .await
+            .unwrap();
+
+        cache.open(&[]).await.unwrap();
+        let names: Vec<String> = cache.state.lock().await.entries.keys().cloned().collect();
+        assert_eq!(names, vec!["u.mp4"]);
+
+        // 5 cached and 20 reserved leave room for 5 more only by evicting the adopted file
+        cache.reserve("b", 10, 10).await.unwrap();
+        assert!(!exists(&dir, "u"));
+        assert!(cache.reserve("c", 10, 10).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn opening_removes_media_that_no_longer_matches_the_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(&dir, 100);
+        download(&cache, &dir, "a").await;
+        download(&cache, &dir, "b").await;
+        fs::write(dir.path().join("b.mp4"), [0u8; 4]).await.unwrap();
+
+        let reopened = MediaCache::new(dir.path(), CacheConfig::default());
+        reopened.open(&[]).await.unwrap();
+        assert!(exists(&dir, "a"));
+        assert!(!exists(&dir, "b"));
+    }
+}
@@ -0,0 +1,345 @@
+use crate::api::{ApiError, ClientActions, SignageApi};
+use crate::util::{data_dir, write_json};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::{boxed::Box, error::Error};
+use tokio::fs;
+use uuid::Uuid;
+
+/// Namespace for the ids of commands made from legacy flags
+const LEGACY_NAMESPACE: Uuid = Uuid::from_u128(0x5f0c_4a8e_2b1d_4c7a_9e36_1d8b_7a42_c953);
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandKind {
+    RestartApp,
+    Reboot,
+    Screenshot,
+    RefreshSchedule,
+    /// Playback control; the parameters are a `PlayerAction`
+    Player,
+}
+
+/// Where a command came from, which decides how it is acknowledged
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandSource {
+    /// The command queue, acknowledged through its status endpoint
+    #[default]
+    Queue,
+    /// One of the old `ClientActions` booleans, acknowledged by clearing the flag
+    LegacyFlag,
+}
+
+/// A remote command as delivered by `/client-commands` or the command socket
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Command {
+    pub id: Uuid,
+    #[serde(rename = "type")]
+    pub kind: CommandKind,
+    #[serde(default)]
+    pub parameters: serde_json::Value,
+    #[serde(default = "Utc::now")]
+    pub issued_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    pub source: CommandSource,
+}
+
+impl Command {
+    /// The id depends only on `kind`, so a flag seen again before it was cleared is the same
+    /// command
+    fn legacy(kind: CommandKind) -> Self {
+        let name = serde_json::to_string(&kind).unwrap_or_default();
+        Command {
+            id: Uuid::new_v5(&LEGACY_NAMESPACE, name.as_bytes()),
+            kind,
+            parameters: serde_json::Value::Null,
+            issued_at: Utc::now(),
+            expires_at: None,
+            source: CommandSource::LegacyFlag,
+        }
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| now > expires_at)
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandStatus {
+    Received,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// Body of a status update for one command
+#[derive(Debug, Serialize)]
+pub struct CommandReport {
+    pub status: CommandStatus,
+    pub error: Option<String>,
+    pub output: Option<String>,
+    pub reported_at: DateTime<Utc>,
+}
+
+impl CommandReport {
+    pub fn new(status: CommandStatus) -> Self {
+        CommandReport {
+            status,
+            error: None,
+            output: None,
+            reported_at: Utc::now(),
+        }
+    }
+
+    pub fn succeeded(output: Option<String>) -> Self {
+        CommandReport {
+            output,
+            ..CommandReport::new(CommandStatus::Succeeded)
+        }
+    }
+
+    pub fn failed(error: impl ToString) -> Self {
+        CommandReport {
+            error: Some(error.to_string()),
+            ..CommandReport::new(CommandStatus::Failed)
+        }
+    }
+}
+
+/// Maps the old boolean flags onto commands, in the order they used to run
+pub fn from_actions(actions: &ClientActions) -> Vec<Command> {
+    [
+        (actions.restart_app, CommandKind::RestartApp),
+        (actions.restart, CommandKind::Reboot),
+        (actions.screenshot, CommandKind::Screenshot),
+    ]
+    .into_iter()
+    .filter(|(set, _)| *set)
+    .map(|(_, kind)| Command::legacy(kind))
+    .collect()
+}
+
+/// Pending commands, falling back to the legacy flags on backends without a command queue.
+///
+/// A command that can't be parsed, such as one of a type this daemon doesn't know, is reported
+/// as failed and skipped so it doesn't hold up the others.
+pub async fn fetch(api: &SignageApi) -> Result<Vec<Command>, ApiError> {
+    let values = match api.commands().await {
+        Err(ApiError::NotFound(_)) => return Ok(from_actions(&api.client_actions().await?)),
+        result => result?,
+    };
+
+    let mut commands = Vec::with_capacity(values.len());
+    for value in values {
+        match serde_json::from_value::<Command>(value.clone()) {
+            Ok(command) => commands.push(command),
+            Err(e) => reject(api, &value, &e.to_string()).await,
+        }
+    }
+    Ok(commands)
+}
+
+/// Reports a command that could not be parsed as failed, if it at least has an id
+async fn reject(api: &SignageApi, value: &serde_json::Value, error: &str) {
+    let id = value
+        .get("id")
+        .and_then(|id| id.as_str())
+        .and_then(|id| Uuid::parse_str(id).ok());
+    eprintln!("Skipping unsupported command {}: {}", value, error);
+
+    let Some(id) = id else {
+        return;
+    };
+    let report = CommandReport::failed(format!("unsupported command: {}", error));
+    if let Err(e) = api.report_command(id, &report).await {
+        eprintln!("Failed to report command {} status: {}", id, e);
+    }
+}
+
+/// Sends `report` for `command`, returning whether the backend got it.
+///
+/// Legacy flags have no status endpoint, so the flag is cleared instead: for a restart or reboot
+/// when it starts `Running`, since the process goes down with it, and for a screenshot once it
+/// `Succeeded`. A screenshot that failed keeps its flag and is tried again on the next poll.
+pub async fn report(
+    api: &SignageApi,
+    command: &Command,
+    report: CommandReport,
+) -> Result<(), ApiError> {
+    println!(
+        "Command {} ({:?}) {:?}{}",
+        command.id,
+        command.kind,
+        report.status,
+        report
+            .error
+            .as_deref()
+            .map(|e| format!(": {}", e))
+            .unwrap_or_default()
+    );
+
+    let result = match (command.source, command.kind, report.status) {
+        (CommandSource::Queue, _, _) => api.report_command(command.id, &report).await,
+        (CommandSource::LegacyFlag, CommandKind::RestartApp, CommandStatus::Running) => {
+            api.update_restart_app_flag().await
+        }
+        (CommandSource::LegacyFlag, CommandKind::Reboot, CommandStatus::Running) => {
+            api.update_restart_flag().await
+        }
+        (CommandSource::LegacyFlag, CommandKind::Screenshot, CommandStatus::Succeeded) => {
+            api.update_screenshot_flag().await
+        }
+        (CommandSource::LegacyFlag, _, _) => Ok(()),
+    };
+
+    if let Err(e) = &result {
+        eprintln!("Failed to report command {} status: {}", command.id, e);
+    }
+    result
+}
+
+/// A restart or reboot that was under way when the daemon went down, kept in
+/// `pending_command.json` until its outcome has been reported
+#[derive(Serialize, Deserialize)]
+struct PendingCommand {
+    command: Command,
+    source: CommandSource,
+}
+
+fn pending_path() -> Result<String, Box<dyn Error>> {
+    Ok(format!("{}/pending_command.json", data_dir()?))
+}
+
+/// Remembers `command` across the restart it is about to cause
+pub async fn save_pending(command: &Command) -> Result<(), Box<dyn Error>> {
+    let pending = PendingCommand {
+        command: command.clone(),
+        source: command.source,
+    };
+    write_json(&pending, &pending_path()?).await
+}
+
+pub async fn clear_pending() {
+    let Ok(path) = pending_path() else {
+        return;
+    };
+    if let Err(e) = fs::remove_file(&path).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            eprintln!("Failed to remove {}: {}", path, e);
+        }
+    }
+}
+
+/// Reports the restart or reboot that brought the daemon up as succeeded.
+///
+/// Its id stays in `seen` so a legacy flag or redelivered command isn't run again, and the
+/// report is retried on every call until the backend has it.
+pub async fn finish_pending(api: &SignageApi, seen: &mut SeenCommands) {
+    let Ok(path) = pending_path() else {
+        return;
+    };
+    let Ok(contents) = fs::read(&path).await else {
+        return;
+    };
+    let pending: PendingCommand = match serde_json::from_slice(&contents) {
+        Ok(pending) => pending,
+        Err(e) => {
+            eprintln!("Discarding unreadable {}: {}", path, e);
+            clear_pending().await;
+            return;
+        }
+    };
+
+    let mut command = pending.command;
+    command.source = pending.source;
+    seen.insert(command.id);
+    if report(api, &command, CommandReport::succeeded(None))
+        .await
+        .is_ok()
+    {
+        clear_pending().await;
+        if command.source == CommandSource::LegacyFlag {
+            // The flag was cleared before the restart, so if it is set again that's a new request
+            seen.forget(command.id);
+        }
+    }
+}
+
+/// Remembers recently handled command ids so a command that is pushed and then polled, or
+/// polled again before its status landed, only runs once
+pub struct SeenCommands {
+    ids: VecDeque<Uuid>,
+    capacity: usize,
+}
+
+impl SeenCommands {
+    pub fn new(capacity: usize) -> Self {
+        SeenCommands {
+            ids: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Records `id`, returning false if it was already seen
+    pub fn insert(&mut self, id: Uuid) -> bool {
+        if self.ids.contains(&id) {
+            return false;
+        }
+        if self.ids.len() == self.capacity {
+            self.ids.pop_front();
+        }
+        self.ids.push_back(id);
+        true
+    }
+
+    /// Lets `id` run again, e.g. a legacy flag that is set again after being cleared
+    pub fn forget(&mut self, id: Uuid) {
+        self.ids.retain(|seen| *seen != id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actions(restart_app: bool, restart: bool, screenshot: bool) -> ClientActions {
+        ClientActions {
+            client_id: Uuid::nil(),
+            restart_app,
+            restart,
+            screenshot,
+        }
+    }
+
+    #[test]
+    fn legacy_flags_keep_their_id_across_polls() {
+        let first = from_actions(&actions(true, false, true));
+        let second = from_actions(&actions(true, true, true));
+
+        assert_eq!(first.len(), 2);
+        assert_eq!(first[0].id, second[0].id);
+        assert_eq!(first[1].id, second[2].id);
+        assert_ne!(second[0].id, second[1].id);
+        assert_ne!(second[1].id, second[2].id);
+    }
+
+    #[test]
+    fn seen_commands_forget_and_evict() {
+        let mut seen = SeenCommands::new(2);
+        let ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+
+        assert!(seen.insert(ids[0]));
+        assert!(!seen.insert(ids[0]));
+        seen.forget(ids[0]);
+        assert!(seen.insert(ids[0]));
+
+        assert!(seen.insert(ids[1]));
+        assert!(seen.insert(ids[2]));
+        // The oldest id made room for the newest
+        assert!(seen.insert(ids[0]));
+    }
+}
@@ -1,6 +1,18 @@
-use crate::util::{load_json, write_json};
+use crate::allowlist::AllowlistConfig;
+use crate::cache::CacheConfig;
+use crate::content::ContentConfig;
+use crate::display::DisplayConfig;
+use crate::downloads::DownloadConfig;
+use crate::player::PlayerConfig;
+use crate::proof_of_play::ProofOfPlayConfig;
+use crate::reporting::MetricsConfig;
+use crate::retry::RetryPolicy;
+use crate::schedule::ScheduleConfig;
+use crate::util::load_json;
+use crate::websocket::WebSocketConfig;
 use serde::{Deserialize, Serialize};
 use std::{boxed::Box, env, error::Error};
+use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct Config {
@@ -9,6 +21,34 @@ pub struct Config {
     pub username: String,
     pub password: String,
     pub key: Option<String>,
+    /// Played when no schedule is active and the backend did not name a fallback playlist
+    #[serde(default)]
+    pub default_playlist_id: Option<Uuid>,
+    /// IANA zone for recurring schedules, e.g. "Europe/London"; defaults to the system zone
+    #[serde(default)]
+    pub timezone: Option<String>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub websocket: WebSocketConfig,
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+    #[serde(default)]
+    pub content: ContentConfig,
+    #[serde(default)]
+    pub downloads: DownloadConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub allowlist: AllowlistConfig,
+    #[serde(default)]
+    pub player: PlayerConfig,
+    #[serde(default)]
+    pub proof_of_play: ProofOfPlayConfig,
+    #[serde(default)]
+    pub display: DisplayConfig,
 }
 
 impl Config {
@@ -25,15 +65,4 @@ impl Config {
         )
         .await
     }
-
-    /// Writes `Config` to $HOME/.config/signage/signage.json
-    pub async fn write(&self) -> Result<(), Box<dyn Error>> {
-        let json_content = serde_json::to_string_pretty(self)?;
-        println!("Writing to signage.json: {}", json_content);
-        write_json(
-            self,
-            &format!("{}/.config/signage/signage.json", env::var("HOME")?),
-        )
-        .await
-    }
 }
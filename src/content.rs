@@ -0,0 +1,396 @@
+use crate::api::SignageApi;
+use crate::config::Config;
+use crate::data::Data;
+use crate::downloads::DownloadManager;
+use crate::player::Player;
+use crate::util::{data_dir, Video};
+use chrono::Utc;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::{boxed::Box, error::Error};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{watch, Notify};
+use tokio::time::{self, Duration};
+use uuid::Uuid;
+
+/// How long to wait before retrying a preload or sync that left assets missing
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Wakes the sync task; a request made while it is busy is kept until it next waits
+static SYNC: Notify = Notify::const_new();
+
+/// Settings under `content` in signage.json
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ContentConfig {
+    /// How long before a scheduled switch its playlist's media starts downloading
+    pub preload_lead_minutes: u64,
+}
+
+impl Default for ContentConfig {
+    fn default() -> Self {
+        ContentConfig {
+            preload_lead_minutes: 60,
+        }
+    }
+}
+
+/// How much of a playlist is on disk, as reported to the backend
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub playlist_id: Uuid,
+    pub assets_total: usize,
+    pub assets_ready: usize,
+    pub ready: bool,
+}
+
+/// An asset the device refused or failed to use, as reported to the backend
+#[derive(Debug, Serialize)]
+pub struct ContentError {
+    pub playlist_id: Uuid,
+    pub asset_id: String,
+    pub asset_url: String,
+    pub reason: String,
+}
+
+/// The `videos` the allowlist permits, reporting the rest to the backend. Retrying those won't
+/// help, so they don't count against a playlist being complete.
+async fn allowed_media(
+    api: &SignageApi,
+    downloads: &DownloadManager,
+    playlist_id: Uuid,
+    videos: &[Video],
+) -> Vec<Video> {
+    let mut allowed = Vec::new();
+    for video in videos {
+        let Err(reason) = downloads.check(video) else {
+            allowed.push(video.clone());
+            continue;
+        };
+
+        eprintln!("Rejecting asset {}: {}", video.id, reason);
+        let error = ContentError {
+            playlist_id,
+            asset_id: video.id.clone(),
+            asset_url: video.asset_url.clone(),
+            reason,
+        };
+        if let Err(e) = api.report_content_error(&error).await {
+            eprintln!("Failed to report rejected asset {}: {}", video.id, e);
+        }
+    }
+    allowed
+}
+
+/// Downloads whatever `videos` are missing, queued in `asset_order`, returning the ones now on
+/// disk along with their paths
+async fn fetch_media(
+    downloads: &DownloadManager,
+    playlist_id: Uuid,
+    videos: &[Video],
+) -> Vec<(Video, String)> {
+    let mut videos = videos.to_vec();
+    videos.sort_by_key(|video| video.asset_order);
+
+    downloads
+        .download_all(playlist_id, &videos)
+        .await
+        .into_iter()
+        .filter_map(|(video, result)| match result {
+            Ok(path) => Some((video, path)),
+            Err(e) => {
+                eprintln!("Failed to download asset {}: {}", video.id, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Fetches the media of `playlist_id` ahead of its start and reports how much of it is ready.
+/// It is pinned as the upcoming playlist, or as the `fallback`.
+async fn preload(
+    api: &SignageApi,
+    downloads: &DownloadManager,
+    playlist_id: Uuid,
+    fallback: bool,
+) -> Result<Readiness, Box<dyn Error>> {
+    let videos = api.playlist_assets(playlist_id).await?;
+    let allowed = allowed_media(api, downloads, playlist_id, &videos).await;
+    if fallback {
+        downloads.cache().pin_fallback(&allowed).await;
+    } else {
+        downloads.cache().pin_upcoming(&allowed).await;
+    }
+    let fetched = fetch_media(downloads, playlist_id, &allowed).await;
+
+    let readiness = Readiness {
+        playlist_id,
+        assets_total: videos.len(),
+        assets_ready: fetched.len(),
+        ready: fetched.len() == allowed.len(),
+    };
+    if let Err(e) = api.report_readiness(&readiness).await {
+        eprintln!(
+            "Failed to report readiness of playlist {}: {}",
+            playlist_id, e
+        );
+    }
+    Ok(readiness)
+}
+
+/// Preloads `playlist_id`, returning whether all of its media is on disk
+async fn preload_all(
+    api: &SignageApi,
+    downloads: &DownloadManager,
+    playlist_id: Uuid,
+    fallback: bool,
+) -> bool {
+    let kind = if fallback { "Fallback" } else { "Upcoming" };
+    println!(
+        "Preloading {} playlist {}",
+        kind.to_lowercase(),
+        playlist_id
+    );
+    match preload(api, downloads, playlist_id, fallback).await {
+        Ok(readiness) if readiness.ready => {
+            println!("{} playlist {} is ready", kind, playlist_id);
+            true
+        }
+        Ok(readiness) => {
+            println!(
+                "{} playlist {}: {} of {} assets ready",
+                kind, playlist_id, readiness.assets_ready, readiness.assets_total
+            );
+            false
+        }
+        Err(e) => {
+            eprintln!("Failed to preload playlist {}: {}", playlist_id, e);
+            false
+        }
+    }
+}
+
+/// The playlist that plays when no schedule is active
+async fn fallback_playlist(config: &watch::Receiver<Config>) -> Option<Uuid> {
+    let default = config.borrow().default_playlist_id;
+    let data = Data::read().await.ok();
+    data.and_then(|data| data.fallback_playlist_id).or(default)
+}
+
+/// Preloads each playlist published on `upcoming` by the scheduler, retrying until all of its
+/// media is on disk or another playlist comes up.
+///
+/// The fallback playlist is kept on disk as well, since it is what plays when the schedule
+/// runs out, often while the backend is unreachable.
+pub async fn run_preloader(
+    client: Client,
+    config: watch::Receiver<Config>,
+    downloads: DownloadManager,
+    mut upcoming: watch::Receiver<Option<Uuid>>,
+) {
+    let mut pending = None;
+    let mut fallback_ready = None;
+
+    loop {
+        let api = SignageApi::new(client.clone(), &config.borrow());
+
+        let fallback = fallback_playlist(&config).await;
+        if fallback != fallback_ready {
+            match fallback {
+                Some(playlist_id) => {
+                    if preload_all(&api, &downloads, playlist_id, true).await {
+                        fallback_ready = fallback;
+                    }
+                }
+                None => {
+                    downloads.cache().pin_fallback(&[]).await;
+                    fallback_ready = None;
+                }
+            }
+        }
+
+        if let Some(playlist_id) = pending {
+            if preload_all(&api, &downloads, playlist_id, false).await {
+                pending = None;
+            }
+        }
+
+        // Woken regularly as well, to pick up a changed fallback playlist
+        tokio::select! {
+            _ = time::sleep(RETRY_DELAY) => (),
+            changed = upcoming.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                pending = *upcoming.borrow_and_update();
+                if pending.is_none() {
+                    downloads.cache().pin_upcoming(&[]).await;
+                }
+            }
+        }
+    }
+}
+
+/// Asks the sync task to bring the media of the current playlist up to date
+pub fn request_sync() {
+    SYNC.notify_one();
+}
+
+/// Where the player reads its playlist from
+pub fn playlist_path() -> Result<String, Box<dyn Error>> {
+    Ok(format!("{}/playlist.txt", data_dir()?))
+}
+
+/// Replaces playlist.txt with `paths`, one per line, through a temporary file so the player
+/// never reads a partial list. Returns false, leaving the file alone, when nothing changed.
+async fn write_playlist(paths: &[String]) -> Result<bool, Box<dyn Error>> {
+    let path = playlist_path()?;
+    let tmp = format!("{}.tmp", path);
+
+    let contents: String = paths.iter().map(|line| format!("{}\n", line)).collect();
+    if fs::read_to_string(&path)
+        .await
+        .is_ok_and(|current| current == contents)
+    {
+        return Ok(false);
+    }
+
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(contents.as_bytes()).await?;
+    file.sync_all().await?;
+    fs::rename(&tmp, &path).await?;
+    Ok(true)
+}
+
+/// Downloads the media of the current playlist, writes playlist.txt and records the result in
+/// data.json. Only once everything is in place is the new playlist loaded into the player, or,
+/// when the daemon doesn't run the player, `update_content` set for whatever does.
+///
+/// Assets that fail or are rejected by the allowlist are left out of the playlist so the rest
+/// can play; `Ok(false)` means the sync should be retried for the failed ones.
+async fn sync(
+    api: &SignageApi,
+    downloads: &DownloadManager,
+    player: &Player,
+) -> Result<bool, Box<dyn Error>> {
+    let Some(playlist_id) = Data::read().await?.current_playlist else {
+        return Ok(true);
+    };
+
+    println!("Syncing content for playlist {}", playlist_id);
+    let videos = api.playlist_assets(playlist_id).await?;
+    let allowed = allowed_media(api, downloads, playlist_id, &videos).await;
+    downloads.cache().pin_current(&allowed).await;
+    let fetched = fetch_media(downloads, playlist_id, &allowed).await;
+    let complete = fetched.len() == allowed.len();
+    if fetched.is_empty() && !allowed.is_empty() {
+        return Err(format!(
+            "none of the {} allowed assets of playlist {} could be fetched",
+            allowed.len(),
+            playlist_id
+        )
+        .into());
+    }
+
+    // Downloads take a while; the schedule may have moved on, in which case another sync is queued
+    if Data::read().await?.current_playlist != Some(playlist_id) {
+        return Ok(true);
+    }
+
+    let paths: Vec<String> = fetched.iter().map(|(_, path)| path.clone()).collect();
+    let changed = write_playlist(&paths).await?;
+
+    // An unchanged list is already playing; reloading it would only restart it
+    let mut loaded = false;
+    if player.enabled() {
+        let playlist = playlist_path()?;
+        loaded = !changed
+            || match player.ipc().load_playlist(&playlist).await {
+                Ok(()) => true,
+                Err(e) => {
+                    eprintln!("Failed to load the playlist into the player: {}", e);
+                    false
+                }
+            };
+    }
+
+    let videos_synced = fetched.into_iter().map(|(video, _)| video).collect();
+    let current = Data::update(|data| {
+        // Switched while loading; the queued sync records the new playlist instead
+        if data.current_playlist != Some(playlist_id) {
+            return false;
+        }
+        data.videos = videos_synced;
+        data.last_update = Some(Utc::now());
+        data.update_content = Some(!loaded);
+        true
+    })
+    .await?;
+    if !current {
+        return Ok(true);
+    }
+    println!(
+        "Playlist {} synced ({} of {} assets)",
+        playlist_id,
+        paths.len(),
+        videos.len()
+    );
+    Ok(complete)
+}
+
+/// Deletes media recorded in data.json that no longer matches its size or checksum, e.g. after
+/// SD card corruption, so the next sync fetches it again
+async fn remove_corrupt_media() -> Result<(), Box<dyn Error>> {
+    let data = Data::read().await?;
+
+    for video in &data.videos {
+        let path = video.file_path()?;
+        if fs::metadata(&path).await.is_err() {
+            continue;
+        }
+        if let Err(e) = video.verify(&path, None).await.map_err(|e| e.to_string()) {
+            eprintln!("Removing corrupt asset {}: {}", path, e);
+            fs::remove_file(&path).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Loads the media index, protecting the media data.json says is playing
+async fn open_cache(downloads: &DownloadManager) -> Result<(), Box<dyn Error>> {
+    let data = Data::read().await?;
+    downloads.cache().open(&data.videos).await
+}
+
+/// Keeps the media of the current playlist in sync: once at startup, whenever `request_sync`
+/// is called, and again after a delay while assets are missing
+pub async fn run_sync(
+    client: Client,
+    config: watch::Receiver<Config>,
+    downloads: DownloadManager,
+    player: Player,
+) {
+    if let Err(e) = remove_corrupt_media().await {
+        eprintln!("Failed to check media on disk: {}", e);
+    }
+    if let Err(e) = open_cache(&downloads).await {
+        eprintln!("Failed to open the media cache: {}", e);
+    }
+
+    loop {
+        let api = SignageApi::new(client.clone(), &config.borrow());
+        let complete = match sync(&api, &downloads, &player).await {
+            Ok(complete) => complete,
+            Err(e) => {
+                eprintln!("Failed to sync content: {}", e);
+                false
+            }
+        };
+
+        tokio::select! {
+            _ = time::sleep(RETRY_DELAY), if !complete => (),
+            _ = SYNC.notified() => (),
+        }
+    }
+}
@@ -1,27 +1,67 @@
+use crate::util::{load_json, write_json, Video};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::{boxed::Box, env, error::Error};
+use tokio::sync::Mutex;
 use uuid::Uuid;
-use crate::util::{load_json, write_json, Video};
+
+/// Held for every read and read-modify-write of data.json, so tasks take turns with it
+static LOCK: Mutex<()> = Mutex::const_new(());
 
 #[derive(Serialize, Deserialize, Default, Clone)]
 pub struct Data {
     pub videos: Vec<Video>,
     pub last_update: Option<DateTime<Utc>>,
     pub current_playlist: Option<Uuid>,
+    /// Schedule whose window put `current_playlist` on screen; `None` for the fallback
+    pub current_schedule: Option<Uuid>,
     pub active_schedule_ends: Option<String>,
     pub next_schedule_starts: Option<String>,
     pub next_playlist_id: Option<Uuid>,
     pub fallback_playlist_id: Option<Uuid>,
+    /// Whether `current_playlist` is the fallback because no schedule is active
+    pub playing_fallback: Option<bool>,
     pub update_content: Option<bool>,
+    /// Layout and rotation the backend asks for
+    pub layout: Option<String>,
+    pub rotation: Option<i32>,
+    /// Layout and rotation last put into effect
+    pub applied_layout: Option<String>,
+    pub applied_rotation: Option<i32>,
 }
 impl Data {
     pub fn new() -> Self {
         Data::default()
     }
 
+    /// data.json as it is now
+    pub async fn read() -> Result<Self, Box<dyn Error>> {
+        let _turn = LOCK.lock().await;
+        let mut data = Data::new();
+        data.load().await?;
+        Ok(data)
+    }
+
+    /// Applies `change` to data.json and returns its result.
+    ///
+    /// The file is reloaded under the lock, so a change never writes back a stale copy over
+    /// another task's (or the player's) edits. `change` can't await, which keeps the turn short:
+    /// anything involving the network happens before or after. Nothing is written when `change`
+    /// leaves the data as it was.
+    pub async fn update<R>(change: impl FnOnce(&mut Data) -> R) -> Result<R, Box<dyn Error>> {
+        let _turn = LOCK.lock().await;
+        let mut data = Data::new();
+        data.load().await?;
+        let before = serde_json::to_vec(&data)?;
+        let result = change(&mut data);
+        if serde_json::to_vec(&data)? != before {
+            data.write().await?;
+        }
+        Ok(result)
+    }
+
     /// Loads `Data` from $HOME/.local/share/signage/data.json
-    pub async fn load(&mut self) -> Result<(), Box<dyn Error>> {
+    async fn load(&mut self) -> Result<(), Box<dyn Error>> {
         println!("Reading data.json: ");
         load_json(
             self,
@@ -31,7 +71,7 @@ impl Data {
         .await
     }
     /// Writes `Data` to $HOME/.local/share/signage/data.json
-    pub async fn write(&self) -> Result<(), Box<dyn Error>> {
+    async fn write(&self) -> Result<(), Box<dyn Error>> {
         println!("Writing to data.json:");
         write_json(
             self,
@@ -0,0 +1,212 @@
+use crate::api::SignageApi;
+use crate::config::Config;
+use crate::data::Data;
+use crate::player::Player;
+use crate::util::run_command;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::{boxed::Box, error::Error};
+use tokio::process::Command;
+use tokio::sync::{watch, Notify};
+
+/// Wakes the display task; a request made while it is busy is kept until it next waits
+static APPLY: Notify = Notify::const_new();
+
+/// Settings under `display` in signage.json
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct DisplayConfig {
+    /// xrandr output to rotate, e.g. "HDMI-1"; the primary, else first connected, when unset
+    pub output: Option<String>,
+}
+
+/// What rotated the picture
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RotatedBy {
+    /// The whole display output, through xrandr
+    Display,
+    /// Only the video, through mpv, because the output could not be rotated
+    Player,
+}
+
+/// The layout and rotation in effect, as reported to the backend
+#[derive(Debug, Serialize)]
+pub struct DisplayState {
+    pub layout: Option<String>,
+    /// The layout the backend asked for, which differs from `layout` when it isn't supported
+    pub requested_layout: Option<String>,
+    pub rotation: i32,
+    pub output: Option<String>,
+    pub rotated_by: RotatedBy,
+}
+
+/// The only layout the player can show: one zone filling the screen
+const FULLSCREEN: &str = "fullscreen";
+
+/// Asks the display task to put the layout and rotation in data.json into effect
+pub fn request_apply() {
+    APPLY.notify_one();
+}
+
+/// The layout that takes effect when the backend asks for `requested`
+fn layout_in_effect(requested: &str) -> &'static str {
+    if !requested.eq_ignore_ascii_case(FULLSCREEN) {
+        eprintln!(
+            "Layout {:?} is not supported, showing {}",
+            requested, FULLSCREEN
+        );
+    }
+    FULLSCREEN
+}
+
+/// xrandr's name for a clockwise rotation
+fn xrandr_rotation(degrees: u32) -> &'static str {
+    match degrees {
+        90 => "right",
+        180 => "inverted",
+        270 => "left",
+        _ => "normal",
+    }
+}
+
+/// The configured output, else the primary one, else the first connected one
+async fn output(config: &DisplayConfig) -> Result<String, Box<dyn Error>> {
+    if let Some(output) = &config.output {
+        return Ok(output.clone());
+    }
+
+    let query = run_command("xrandr", &["--query"]).await?;
+    let connected: Vec<&str> = query
+        .lines()
+        .filter(|line| line.contains(" connected"))
+        .collect();
+    connected
+        .iter()
+        .find(|line| line.contains(" primary"))
+        .or(connected.first())
+        .and_then(|line| line.split_whitespace().next())
+        .map(str::to_string)
+        .ok_or_else(|| "xrandr reports no connected output".into())
+}
+
+async fn rotate_output(output: &str, degrees: u32) -> Result<(), Box<dyn Error>> {
+    let result = Command::new("xrandr")
+        .args(["--output", output, "--rotate", xrandr_rotation(degrees)])
+        .output()
+        .await?;
+    if !result.status.success() {
+        return Err(format!(
+            "xrandr failed: {}",
+            String::from_utf8_lossy(&result.stderr).trim()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Rotates the display output by `rotation` degrees clockwise, or the player's video when the
+/// output can't be rotated. The player is turned back upright when the output takes over.
+async fn rotate(
+    config: &DisplayConfig,
+    player: &Player,
+    rotation: i32,
+) -> Result<(Option<String>, RotatedBy), String> {
+    let degrees = rotation.rem_euclid(360) as u32;
+    if degrees % 90 != 0 {
+        return Err(format!("unsupported rotation {}", rotation));
+    }
+
+    let output = output(config).await.map_err(|e| e.to_string());
+    let rotated = match &output {
+        Ok(output) => rotate_output(output, degrees)
+            .await
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e.clone()),
+    };
+
+    let (player_degrees, rotated_by) = match rotated {
+        Ok(()) => (0, RotatedBy::Display),
+        Err(e) => {
+            eprintln!(
+                "Failed to rotate the display, rotating the video instead: {}",
+                e
+            );
+            (degrees, RotatedBy::Player)
+        }
+    };
+    if let Err(e) = player.set_rotation(player_degrees).await {
+        // A player launched later picks the rotation up anyway
+        if rotated_by == RotatedBy::Player {
+            eprintln!("Failed to rotate the player: {}", e);
+        }
+    }
+    Ok((
+        output.ok().filter(|_| rotated_by == RotatedBy::Display),
+        rotated_by,
+    ))
+}
+
+/// Puts the layout and rotation from data.json into effect unless they already are, or always
+/// when `force` is set, and reports what took effect
+async fn apply(
+    api: &SignageApi,
+    config: &DisplayConfig,
+    player: &Player,
+    force: bool,
+) -> Result<(), String> {
+    let data = Data::read().await.map_err(|e| e.to_string())?;
+
+    let requested_layout = data.layout.clone();
+    let rotation = data.rotation.or(data.applied_rotation);
+    if requested_layout.is_none() && rotation.is_none() {
+        return Ok(());
+    }
+    let layout = requested_layout
+        .as_deref()
+        .map(|requested| layout_in_effect(requested).to_string())
+        .or(data.applied_layout.clone());
+    let rotation = rotation.unwrap_or(0);
+    if !force && data.applied_layout == layout && data.applied_rotation == Some(rotation) {
+        return Ok(());
+    }
+
+    println!("Applying layout {:?}, rotation {}", layout, rotation);
+    let (output, rotated_by) = rotate(config, player, rotation).await?;
+
+    // Rotating can take a moment; keep whatever else changed in data.json meanwhile
+    let applied = layout.clone();
+    Data::update(|data| {
+        data.applied_layout = applied;
+        data.applied_rotation = Some(rotation);
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let state = DisplayState {
+        layout,
+        requested_layout,
+        rotation,
+        output,
+        rotated_by,
+    };
+    if let Err(e) = api.report_display(&state).await {
+        eprintln!("Failed to report the display state: {}", e);
+    }
+    Ok(())
+}
+
+/// Applies the layout and rotation once at startup, since the display comes up unrotated, and
+/// again whenever `request_apply` is called
+pub async fn run(client: Client, config: watch::Receiver<Config>, player: Player) {
+    let mut force = true;
+    loop {
+        let current = config.borrow().clone();
+        let api = SignageApi::new(client.clone(), &current);
+        if let Err(e) = apply(&api, &current.display, &player, force).await {
+            eprintln!("Failed to apply the layout: {}", e);
+        }
+        force = false;
+        APPLY.notified().await;
+    }
+}
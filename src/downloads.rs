@@ -0,0 +1,331 @@
+use crate::allowlist::AllowlistConfig;
+use crate::cache::MediaCache;
+use crate::recurrence::Days;
+use crate::telemetry::{finish_download, set_download_progress, DownloadProgress};
+use crate::util::Video;
+use chrono::{DateTime, Datelike, NaiveTime, Utc};
+use chrono_tz::Tz;
+use futures_util::future::join_all;
+use reqwest::{redirect, Client};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{Mutex, Semaphore};
+use tokio::time::{self, Duration, Instant};
+use uuid::Uuid;
+
+/// Redirects a media download may follow, as many as reqwest follows by default
+const MAX_REDIRECTS: usize = 10;
+
+/// An HTTP client for media that checks every redirect against `allowlist` too, so an allowed
+/// host can't hand the download to one that isn't
+fn media_client(allowlist: AllowlistConfig) -> Result<Client, reqwest::Error> {
+    let policy = redirect::Policy::custom(move |attempt| {
+        if attempt.previous().len() > MAX_REDIRECTS {
+            return attempt.error("too many redirects");
+        }
+        match allowlist.check(attempt.url().as_str()) {
+            Ok(()) => attempt.follow(),
+            Err(e) => attempt.error(format!("redirect refused: {}", e)),
+        }
+    });
+    Client::builder().redirect(policy).build()
+}
+
+/// Settings under `downloads` in signage.json
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct DownloadConfig {
+    /// Transfers allowed to run at once
+    pub max_parallel: usize,
+    /// Cap on the combined download rate; unlimited when unset
+    pub max_bytes_per_sec: Option<u64>,
+    /// A different cap while the venue is open
+    pub business_hours: Option<BusinessHours>,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        DownloadConfig {
+            max_parallel: 2,
+            max_bytes_per_sec: None,
+            business_hours: None,
+        }
+    }
+}
+
+/// Local opening hours during which `max_bytes_per_sec` replaces the normal cap. An `end` at
+/// or before `start` runs past midnight, into the day after one of `days`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BusinessHours {
+    #[serde(default)]
+    pub days: Days,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Unlimited when unset
+    pub max_bytes_per_sec: Option<u64>,
+}
+
+impl BusinessHours {
+    fn contains(&self, now: DateTime<Tz>) -> bool {
+        let time = now.time();
+        if self.start < self.end {
+            self.days.contains(now.weekday()) && self.start <= time && time < self.end
+        } else {
+            (self.days.contains(now.weekday()) && self.start <= time)
+                || (self.days.contains(now.weekday().pred()) && time < self.end)
+        }
+    }
+}
+
+/// A global token bucket shared by every transfer, holding at most one second of data
+pub struct Bandwidth {
+    config: DownloadConfig,
+    tz: Tz,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl Bandwidth {
+    fn new(config: DownloadConfig, tz: Tz) -> Self {
+        Bandwidth {
+            config,
+            tz,
+            bucket: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    /// The cap in force right now, if any
+    fn rate(&self) -> Option<u64> {
+        let now = Utc::now().with_timezone(&self.tz);
+        let open = self
+            .config
+            .business_hours
+            .as_ref()
+            .filter(|hours| hours.contains(now));
+        match open {
+            Some(hours) => hours.max_bytes_per_sec,
+            None => self.config.max_bytes_per_sec,
+        }
+        .filter(|rate| *rate > 0)
+    }
+
+    /// Waits until `bytes` more may be received. Transfers queue on the bucket, so the cap
+    /// holds for all of them together.
+    async fn throttle(&self, bytes: usize) {
+        let Some(rate) = self.rate().map(|rate| rate as f64) else {
+            return;
+        };
+
+        let mut bucket = self.bucket.lock().await;
+        let (available, refilled) = &mut *bucket;
+        *available =
+            (*available + refilled.elapsed().as_secs_f64() * rate).min(rate) - bytes as f64;
+        *refilled = Instant::now();
+
+        if *available < 0.0 {
+            time::sleep(Duration::from_secs_f64(-*available / rate)).await;
+        }
+    }
+
+    /// Throttles a received chunk of `bytes` and records how far `asset_id` has got
+    pub async fn received(
+        &self,
+        asset_id: &str,
+        bytes: usize,
+        downloaded: u64,
+        total: Option<u64>,
+    ) {
+        set_download_progress(DownloadProgress {
+            asset_id: asset_id.to_string(),
+            downloaded_bytes: downloaded,
+            total_bytes: total,
+        });
+        self.throttle(bytes).await;
+    }
+}
+
+/// Runs `Video::download` for allowlisted URLs with a limit on parallel transfers and a shared
+/// bandwidth cap.
+/// Cheap to clone; clones share the limits.
+#[derive(Clone)]
+pub struct DownloadManager {
+    client: Client,
+    slots: Arc<Semaphore>,
+    bandwidth: Arc<Bandwidth>,
+    cache: Arc<MediaCache>,
+    allowlist: Arc<AllowlistConfig>,
+    /// One lock per asset, so the sync and preload tasks never write the same `.part` file
+    assets: Arc<std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>>,
+}
+
+impl DownloadManager {
+    pub fn new(
+        config: &DownloadConfig,
+        cache: MediaCache,
+        allowlist: AllowlistConfig,
+        tz: Tz,
+    ) -> Result<Self, reqwest::Error> {
+        Ok(DownloadManager {
+            client: media_client(allowlist.clone())?,
+            slots: Arc::new(Semaphore::new(config.max_parallel.max(1))),
+            bandwidth: Arc::new(Bandwidth::new(config.clone(), tz)),
+            cache: Arc::new(cache),
+            allowlist: Arc::new(allowlist),
+            assets: Arc::default(),
+        })
+    }
+
+    pub fn cache(&self) -> &MediaCache {
+        &self.cache
+    }
+
+    /// Whether `video` may be downloaded under the allowlist, and why not
+    pub fn check(&self, video: &Video) -> Result<(), String> {
+        self.allowlist.check(&video.asset_url)
+    }
+
+    fn asset_lock(&self, asset_id: &str) -> Arc<Mutex<()>> {
+        self.assets
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(asset_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Downloads one asset of `playlist_id` once a transfer slot is free, provided the cache has
+    /// room for it, returning its path
+    pub async fn download(&self, playlist_id: Uuid, video: &Video) -> Result<String, String> {
+        self.check(video)?;
+        let asset = self.asset_lock(&video.id);
+        let _asset = asset.lock().await;
+        let _slot = self.slots.acquire().await.map_err(|e| e.to_string())?;
+
+        let result = video
+            .download(&self.client, &self.bandwidth, &self.cache)
+            .await
+            .map_err(|e| e.to_string());
+        finish_download(&video.id);
+
+        if let Ok(path) = &result {
+            if let Err(e) = self.cache.record(playlist_id, video, path).await {
+                eprintln!("Failed to record {} in the media index: {}", path, e);
+            }
+        }
+        self.cache.release(&video.id).await;
+        result
+    }
+
+    /// Downloads the `videos` of `playlist_id` concurrently, returning each with its outcome in
+    /// the order given
+    pub async fn download_all(
+        &self,
+        playlist_id: Uuid,
+        videos: &[Video],
+    ) -> Vec<(Video, Result<String, String>)> {
+        let results = join_all(videos.iter().map(|video| self.download(playlist_id, video))).await;
+        videos.iter().cloned().zip(results).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Answers `/file` with a body and any other path with a redirect to `{base}/file`, where
+    /// `base` is the path with its leading slash dropped, e.g. `/http://localhost:1` redirects
+    /// to `http://localhost:1/file`
+    async fn serve(listener: TcpListener) {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0u8; 4096];
+            let read = stream.read(&mut request).await.unwrap();
+            let request = String::from_utf8_lossy(&request[..read]).to_string();
+            let path = request.split_whitespace().nth(1).unwrap_or_default();
+            let response = match path {
+                "/file" => "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nmedia".to_string(),
+                _ => format!(
+                    "HTTP/1.1 302 Found\r\nLocation: {}/file\r\nContent-Length: 0\r\n\r\n",
+                    &path[1..]
+                ),
+            };
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    }
+
+    fn allowlist(hosts: &[&str]) -> AllowlistConfig {
+        AllowlistConfig {
+            hosts: hosts.iter().map(|host| host.to_string()).collect(),
+            https_only: false,
+        }
+    }
+
+    fn hours(days: Days, start: &str, end: &str) -> BusinessHours {
+        BusinessHours {
+            days,
+            start: NaiveTime::parse_from_str(start, "%H:%M").unwrap(),
+            end: NaiveTime::parse_from_str(end, "%H:%M").unwrap(),
+            max_bytes_per_sec: None,
+        }
+    }
+
+    #[test]
+    fn business_hours_can_run_past_midnight() {
+        use chrono::{TimeZone, Weekday};
+        use chrono_tz::Europe::London;
+
+        let daytime = hours(Days::default(), "08:00", "18:00");
+        let fridays = hours(Days::On(vec![Weekday::Fri]), "22:00", "02:00");
+        // 2026-10-16 is a Friday
+        let cases = [
+            (&daytime, (16, 12, 0), true),
+            (&daytime, (16, 18, 0), false),
+            (&daytime, (16, 7, 59), false),
+            (&fridays, (16, 21, 59), false),
+            (&fridays, (16, 22, 0), true),
+            (&fridays, (17, 1, 59), true),
+            (&fridays, (17, 2, 0), false),
+            (&fridays, (17, 23, 0), false),
+            (&fridays, (16, 1, 0), false),
+        ];
+        for (hours, (day, hour, minute), open) in cases {
+            let now = London
+                .with_ymd_and_hms(2026, 10, day, hour, minute, 0)
+                .unwrap();
+            assert_eq!(hours.contains(now), open, "{:?} at {}", hours, now);
+        }
+    }
+
+    #[tokio::test]
+    async fn redirects_are_checked_against_the_allowlist() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve(listener));
+        let client = media_client(allowlist(&["127.0.0.1"])).unwrap();
+
+        // Redirected within the allowlist
+        let url = format!("http://127.0.0.1:{port}/http://127.0.0.1:{port}");
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "media");
+
+        // Redirected to a host that isn't allowed, although it is the same server
+        let url = format!("http://127.0.0.1:{port}/http://localhost:{port}");
+        let error = client.get(&url).send().await.unwrap_err();
+        assert!(error.is_redirect(), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn redirect_loops_are_cut_short() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve(listener));
+        let client = media_client(allowlist(&["127.0.0.1"])).unwrap();
+
+        // `x/file` is relative, so every hop lands on a longer path that redirects again
+        let url = format!("http://127.0.0.1:{port}/x");
+        let error = client.get(&url).send().await.unwrap_err();
+        assert!(error.is_redirect(), "{}", error);
+    }
+}
@@ -0,0 +1,258 @@
+use crate::reporting::Metrics;
+use crate::telemetry::{read, COUNTERS};
+use std::fmt::Write as _;
+use std::{boxed::Box, error::Error};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::time::{self, Duration};
+
+/// Largest request head we are willing to read from a scraper
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+/// How long a client gets to send its request head before the connection is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves the latest sample and the daemon counters at `GET /metrics` in the Prometheus text format
+pub async fn serve(
+    listen: &str,
+    latest: watch::Receiver<Option<Metrics>>,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(listen).await?;
+    println!("Serving Prometheus metrics on {}", listener.local_addr()?);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let latest = latest.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, latest).await {
+                eprintln!("Failed to answer metrics request from {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Reads up to the end of the request head, or `None` if it is larger than `MAX_REQUEST_BYTES`
+async fn read_request(stream: &mut TcpStream) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let mut request = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        if request.len() + read > MAX_REQUEST_BYTES {
+            return Ok(None);
+        }
+        request.extend_from_slice(&chunk[..read]);
+    }
+    Ok(Some(request))
+}
+
+async fn handle(
+    mut stream: TcpStream,
+    latest: watch::Receiver<Option<Metrics>>,
+) -> Result<(), Box<dyn Error>> {
+    let request = time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| "timed out waiting for the request")??;
+
+    let too_large = request.is_none();
+    let request = String::from_utf8_lossy(request.as_deref().unwrap_or_default());
+    let mut request_line = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    let (status, content_type, body) = match (method, path.split('?').next()) {
+        _ if too_large => (
+            "431 Request Header Fields Too Large",
+            "text/plain",
+            "request too large\n".to_string(),
+        ),
+        ("GET", Some("/metrics")) => {
+            let body = render(latest.borrow().as_ref());
+            ("200 OK", "text/plain; version=0.0.4; charset=utf-8", body)
+        }
+        ("GET", _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_string(),
+        ),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn gauge<T: std::fmt::Display>(out: &mut String, name: &str, help: &str, value: Option<T>) {
+    if let Some(value) = value {
+        metric(out, name, "gauge", help, value);
+    }
+}
+
+/// Escapes a label value per the exposition format
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render(metrics: Option<&Metrics>) -> String {
+    let mut out = String::new();
+
+    if let Some(m) = metrics {
+        let _ = writeln!(out, "# HELP signage_info Daemon and platform information");
+        let _ = writeln!(out, "# TYPE signage_info gauge");
+        let _ = writeln!(
+            out,
+            "signage_info{{client_id=\"{}\",version=\"{}\",arch=\"{}\",os=\"{}\"}} 1",
+            label(&m.client_id),
+            label(&m.daemon_version),
+            label(&m.chip_architecture),
+            label(&m.os)
+        );
+        gauge(
+            &mut out,
+            "signage_temperature_celsius",
+            "Hottest thermal zone",
+            m.temp_celsius,
+        );
+        gauge(
+            &mut out,
+            "signage_cpu_usage_percent",
+            "CPU busy time since the previous sample",
+            m.cpu_percent,
+        );
+        gauge(
+            &mut out,
+            "signage_memory_used_bytes",
+            "Memory in use",
+            m.memory_used_bytes,
+        );
+        gauge(
+            &mut out,
+            "signage_memory_total_bytes",
+            "Total memory",
+            m.memory_total_bytes,
+        );
+        gauge(
+            &mut out,
+            "signage_disk_used_bytes",
+            "Root filesystem space in use",
+            m.disk_used_bytes,
+        );
+        gauge(
+            &mut out,
+            "signage_disk_total_bytes",
+            "Root filesystem size",
+            m.disk_total_bytes,
+        );
+        gauge(
+            &mut out,
+            "signage_swap_used_bytes",
+            "Swap in use",
+            m.swap_used_bytes,
+        );
+        gauge(
+            &mut out,
+            "signage_swap_total_bytes",
+            "Total swap",
+            m.swap_total_bytes,
+        );
+        gauge(
+            &mut out,
+            "signage_uptime_seconds",
+            "Time since boot",
+            m.uptime_seconds,
+        );
+        gauge(
+            &mut out,
+            "signage_mpv_running",
+            "1 when the mpv player is running",
+            Some(u8::from(m.mpv_running)),
+        );
+        gauge(
+            &mut out,
+            "signage_schedule_cached",
+            "1 while playing from the cached schedule",
+            Some(u8::from(m.schedule_cached)),
+        );
+        gauge(
+            &mut out,
+            "signage_schedule_age_seconds",
+            "Time since the backend last confirmed the schedule",
+            m.schedule_age_seconds,
+        );
+        gauge(
+            &mut out,
+            "signage_downloads_in_progress",
+            "Media transfers running when sampled",
+            Some(m.downloads.len()),
+        );
+        gauge(
+            &mut out,
+            "signage_last_sample_timestamp_seconds",
+            "When the vitals above were sampled",
+            Some(m.timestamp.timestamp()),
+        );
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP signage_api_requests_total Backend requests by outcome"
+    );
+    let _ = writeln!(out, "# TYPE signage_api_requests_total counter");
+    let _ = writeln!(
+        out,
+        "signage_api_requests_total{{result=\"success\"}} {}",
+        read(&COUNTERS.api_success)
+    );
+    let _ = writeln!(
+        out,
+        "signage_api_requests_total{{result=\"failure\"}} {}",
+        read(&COUNTERS.api_failure)
+    );
+    metric(
+        &mut out,
+        "signage_downloads_total",
+        "counter",
+        "Media files downloaded",
+        read(&COUNTERS.downloads),
+    );
+    metric(
+        &mut out,
+        "signage_schedule_switches_total",
+        "counter",
+        "Playlist switches made by the scheduler",
+        read(&COUNTERS.schedule_switches),
+    );
+    metric(
+        &mut out,
+        "signage_mpv_restarts_total",
+        "counter",
+        "Times the player was restarted",
+        read(&COUNTERS.mpv_restarts),
+    );
+
+    out
+}
@@ -1,32 +1,59 @@
-use chrono::{DateTime, Utc, Duration as ChronoDuration}; 
+use api::{ClientPlaylistSchedule, SignageApi};
+use cache::MediaCache;
+use chrono::Utc;
+use commands::{CommandKind, CommandReport, CommandSource, CommandStatus, SeenCommands};
 use config::Config;
-use data::Data;
-use reporting::{collect_and_write_metrics, send_metrics};
-use reqwest::multipart::{Form, Part};
-use reqwest::{Client, StatusCode};
-use serde::{Deserialize, Serialize};
-use serde_json::json;
+use downloads::DownloadManager;
+use player::{Player, PlayerAction};
+use recurrence::device_timezone;
+use reporting::send_metrics;
+use reqwest::Client;
 use std::env;
 use std::fs::File;
 use std::io::Read;
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::{boxed::Box, error::Error};
+use telemetry::{count, COUNTERS};
 use tokio::process::Command;
-use tokio::signal::unix::{signal, SignalKind};
-use tokio::time::{self, Duration as TokioDuration};
-use util::{set_display};
+use tokio::sync::{mpsc, watch};
+use tokio::time;
+use util::{data_dir, restart_service, set_display};
 use uuid::Uuid;
 
+mod allowlist;
+mod api;
+mod buffer;
+mod cache;
+mod commands;
 mod config;
+mod content;
+mod data;
+mod display;
+mod downloads;
+mod exporter;
+mod player;
+mod proof_of_play;
+mod recurrence;
 mod reporting;
+mod resolver;
+mod retry;
+mod schedule;
+mod sysinfo;
+mod telemetry;
 mod util;
-mod data;
+mod watchdog;
+mod websocket;
+
+/// Daemon version reported by `--version` and in the metrics payload
+pub const VERSION: &str = "v1.0.1";
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     // Add --version flag support at the very top
     if std::env::args().any(|arg| arg == "--version") {
-        println!("v1.0.1");
+        println!("{}", VERSION);
         std::process::exit(0);
     }
 
@@ -43,300 +70,302 @@ async fn main() -> Result<(), Box<dyn Error>> {
         return Ok(());
     }
 
-    let mut metrics_interval = time::interval(TokioDuration::from_secs(30)); 
+    // The config as of the last reload, so tasks talk to the backend with a rotated key
+    let (config_tx, config_rx) = watch::channel(config.clone());
+
+    // The schedule list is fetched by the main loop and switched on by the scheduler task. It
+    // starts from the cached copy so playback follows the schedule even if the backend is down.
+    let (schedules, schedules_rx) = watch::channel(schedule::load_cache(&config.schedule).await);
+    let (upcoming_tx, upcoming_rx) = watch::channel(None);
+    tokio::spawn(schedule::run(
+        client.clone(),
+        config_rx.clone(),
+        schedules_rx,
+        upcoming_tx,
+    ));
+    let downloads = DownloadManager::new(
+        &config.downloads,
+        MediaCache::new(data_dir()?, config.cache.clone()),
+        config.allowlist.clone(),
+        device_timezone(config.timezone.as_deref()),
+    )?;
+    tokio::spawn(content::run_preloader(
+        client.clone(),
+        config_rx.clone(),
+        downloads.clone(),
+        upcoming_rx,
+    ));
+    let player = Player::new(&config.player)?;
+    if player.enabled() {
+        if let Err(e) = player.launch(&content::playlist_path()?).await {
+            eprintln!("Failed to start the player: {}", e);
+        }
+        tokio::spawn(watchdog::run(
+            client.clone(),
+            config_rx.clone(),
+            player.clone(),
+        ));
+        if config.proof_of_play.enabled {
+            tokio::spawn(proof_of_play::run(
+                client.clone(),
+                config_rx.clone(),
+                player.clone(),
+            ));
+        }
+    }
+    tokio::spawn(display::run(
+        client.clone(),
+        config_rx.clone(),
+        player.clone(),
+    ));
+    tokio::spawn(content::run_sync(
+        client.clone(),
+        config_rx.clone(),
+        downloads,
+        player.clone(),
+    ));
+
+    // Vitals are sampled from the start, so the Prometheus exporter works without the backend
+    let (latest_tx, mut latest_rx) = watch::channel(None);
+    tokio::spawn(reporting::run_sampler(
+        config.id.clone(),
+        player.clone(),
+        latest_tx,
+    ));
+    if let Some(listen) = config.metrics.exporter_listen.clone() {
+        let latest_rx = latest_rx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = exporter::serve(&listen, latest_rx).await {
+                eprintln!("Prometheus exporter stopped: {}", e);
+            }
+        });
+    }
+
+    // Don't start polling until the backend is reachable
+    wait_for_api(&SignageApi::new(client.clone(), &config)).await;
+
+    // Settle a restart or reboot this daemon came up from before taking new commands
+    let mut seen_commands = SeenCommands::new(256);
+    commands::finish_pending(
+        &SignageApi::new(client.clone(), &config),
+        &mut seen_commands,
+    )
+    .await;
+
+    // Pushed commands; while the socket is up we skip polling for actions
+    let (command_tx, mut command_rx) = mpsc::channel(16);
+    let socket_connected = Arc::new(AtomicBool::new(false));
+    if config.websocket.enabled {
+        tokio::spawn(websocket::run(
+            config_rx,
+            command_tx,
+            socket_connected.clone(),
+        ));
+    }
 
     loop {
         tokio::select! {
-            _ = metrics_interval.tick() => {
+            // Each new sample also paces polling; one taken while the backend was down is sent now
+            Ok(()) = latest_rx.changed() => {
+                let Some(metrics) = latest_rx.borrow_and_update().clone() else {
+                    continue;
+                };
+
                 // Reload configuration to get the latest API key
                 if let Err(e) = config.load().await {
                     eprintln!("Failed to reload configuration: {}", e);
                     continue; // Skip this iteration if config reload fails
                 }
+                config_tx.send_replace(config.clone());
+
+                if config.key.is_some() {
+                    let api = SignageApi::new(client.clone(), &config);
 
-                if let Some(api_key) = &config.key {
-                    // Collect and send metrics
-                    let metrics = collect_and_write_metrics(&config.id).await;
                     println!("Sending vitals");
-                    send_metrics(&config.id, &metrics, api_key, &config);
+                    if let Err(e) = send_metrics(&api, &metrics, &config.metrics).await {
+                        eprintln!("Failed to send metrics: {}", e);
+                    }
 
-                    // Check client actions
-                    if let Some(actions) = get_client_actions(&client, &config).await {
-                        if actions.restart_app {
-                            restart_app(&client, &config).await;
-                        }
-                        if actions.restart {
-                            restart_device(&client, &config).await;
-                        }
-                        if actions.screenshot {
-                            if let Err(e) = take_screenshot(&client, &config).await {
-                                eprintln!("Failed to take screenshot: {}", e);
+                    commands::finish_pending(&api, &mut seen_commands).await;
+
+                    // Check for commands, unless they are being pushed to us
+                    if !socket_connected.load(Ordering::Relaxed) {
+                        match commands::fetch(&api).await {
+                            Ok(pending) => {
+                                for command in pending {
+                                    handle_command(&api, &config, &player, command, &mut seen_commands, &schedules).await;
+                                }
                             }
+                            Err(e) => println!("Failed to retrieve client commands: {}", e),
                         }
                     }
 
-                    //Check for playlist schedule updates
-                     println!("Updating Schedule");
-                     if let Some(schedules) = get_client_playlist_schedule(&client, &config).await {
-                        if let Err(e) = process_schedules(&client, &config, schedules).await {
-                            eprintln!("Error processing schedules: {}", e);
-                        }
+                    if let Err(e) = schedule::update(&api, &config.schedule, &schedules, false).await {
+                        eprintln!("Error updating schedule: {}", e);
                     }
                 } else {
                     eprintln!("API key is missing. Skipping operations.");
                 }
             }
-        }
-    }
-}
-
-async fn wait_for_api(client: &Client, config: &Config) -> Result<bool, Box<dyn Error>> {
-    let mut interval = time::interval(TokioDuration::from_secs(1)); 
-    loop {
-        let res = client.get(format!("{}/health", config.url)).send().await;
-        if let Ok(response) = res {
-            match response.status() {
-                StatusCode::OK => break,
-                StatusCode::INTERNAL_SERVER_ERROR => {
-                    println!("Server error. Retrying in 2 minutes...");
-                    time::interval(TokioDuration::from_secs(120)).tick().await;
-                }
-                _ => (),
+            Some(command) = command_rx.recv() => {
+                let api = SignageApi::new(client.clone(), &config);
+                handle_command(&api, &config, &player, command, &mut seen_commands, &schedules).await;
             }
         }
-        interval.tick().await;
     }
-    Ok(true)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ClientActions {
-    pub client_id: Uuid,
-    pub restart_app: bool,
-    pub restart: bool,
-    pub screenshot: bool,
-}
-
-async fn get_client_actions(client: &Client, config: &Config) -> Option<ClientActions> {
-    let res = client
-        .get(format!("{}/client-actions/{}", config.url, config.id))
-        .header("APIKEY", config.key.clone().unwrap_or_default())
-        .send()
-        .await
-        .ok()?;
-
-    if res.status().is_success() {
-        res.json::<ClientActions>().await.ok()
-    } else {
-        println!("Failed to retrieve client actions: {:?}", res.status());
-        None
+/// Runs a command, whether it was polled or pushed over the socket, reporting each step
+async fn handle_command(
+    api: &SignageApi,
+    config: &Config,
+    player: &Player,
+    command: commands::Command,
+    seen: &mut SeenCommands,
+    schedules: &watch::Sender<Option<Vec<ClientPlaylistSchedule>>>,
+) {
+    if !seen.insert(command.id) {
+        return;
     }
-}
-
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ClientPlaylistSchedule {
-    pub id: Uuid,
-    pub playlist_id: Uuid,
-    pub device_id: Uuid,
-    pub organization_id: Uuid,
-    pub start_time: DateTime<Utc>,
-    pub end_time: DateTime<Utc>,
-    pub playlist_name: String,
-    pub created_at: Option<DateTime<Utc>>,
-    pub updated_at: Option<DateTime<Utc>>,
-}
+    if command.is_expired(Utc::now()) {
+        let report = CommandReport::failed("expired before it could run");
+        let _ = commands::report(api, &command, report).await;
+        return;
+    }
+    let _ = commands::report(api, &command, CommandReport::new(CommandStatus::Received)).await;
 
-async fn get_client_playlist_schedule(client: &Client, config: &Config) -> Option<Vec<ClientPlaylistSchedule>> {
-    let res = client
-        .get(format!("{}/client-playlists_schedule/{}", config.url, config.id))
-        .header("APIKEY", config.key.clone().unwrap_or_default())
-        .send()
-        .await
-        .ok()?;
+    if matches!(command.kind, CommandKind::RestartApp | CommandKind::Reboot) {
+        restart(api, player, command, seen).await;
+        return;
+    }
 
-    if res.status().is_success() {
-        res.json::<Vec<ClientPlaylistSchedule>>().await.ok() // Deserialize to Vec<ClientPlaylistSchedule>
-    } else {
-        println!("Failed to retrieve client playlist schedule: {:?}", res.status());
-        None
+    let _ = commands::report(api, &command, CommandReport::new(CommandStatus::Running)).await;
+    let result = match command.kind {
+        CommandKind::Screenshot => take_screenshot(api).await.map(|()| None),
+        // The current list keeps playing if the refetch fails
+        CommandKind::RefreshSchedule => schedule::update(api, &config.schedule, schedules, true)
+            .await
+            .map(|()| None),
+        CommandKind::Player => control_player(player, &command.parameters).await,
+        // Handled above
+        CommandKind::RestartApp | CommandKind::Reboot => return,
+    };
+
+    let report = match result {
+        Ok(output) => CommandReport::succeeded(output),
+        Err(e) => CommandReport::failed(e),
+    };
+    let _ = commands::report(api, &command, report).await;
+    if command.source == CommandSource::LegacyFlag {
+        // Either the flag was cleared, and setting it again is a new request, or it is still
+        // set and the command should be tried again on the next poll
+        seen.forget(command.id);
     }
 }
 
-async fn restart_app(client: &Client, config: &Config) {
-    let update_result = update_restart_app_flag(client, config).await;
-
-    if let Err(e) = update_result {
-        println!("Failed to update restart flag: {}", e);
+/// Runs a restart app or reboot command. These take this process down with them, so the
+/// command is saved first and reported as succeeded by `commands::finish_pending` once the
+/// daemon is back.
+async fn restart(
+    api: &SignageApi,
+    player: &Player,
+    command: commands::Command,
+    seen: &mut SeenCommands,
+) {
+    // Without the acknowledgement, which also clears a legacy flag, the command would run again
+    // after the restart; leave it for the next poll instead
+    if commands::report(api, &command, CommandReport::new(CommandStatus::Running))
+        .await
+        .is_err()
+    {
+        seen.forget(command.id);
         return;
     }
-
-    println!("Restarting Signage Application...");
-    let stop_mpv_output = Command::new("pkill").arg("mpv").output().await;
-
-    match stop_mpv_output {
-        Ok(output) if output.status.success() => {
-            println!("MPV player stopped successfully.");
-        }
-        Ok(output) => {
-            eprintln!(
-                "Failed to stop MPV player: {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
-        }
-        Err(e) => {
-            eprintln!("Failed to execute stop MPV command: {}", e);
-        }
+    if let Err(e) = commands::save_pending(&command).await {
+        let report = CommandReport::failed(format!("Failed to save the command: {}", e));
+        let _ = commands::report(api, &command, report).await;
+        return;
     }
 
-    let restart_service_output = Command::new("sudo")
-        .arg("systemctl")
-        .arg("restart")
-        .arg("signaged.service")
-        .output()
-        .await;
-
-    match restart_service_output {
-        Ok(output) if output.status.success() => {
-            println!("Signage service restarted successfully.");
-        }
-        Ok(output) => {
-            eprintln!(
-                "Failed to restart signage service: {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
-        }
-        Err(e) => {
-            eprintln!("Failed to execute restart command: {}", e);
+    let result = if command.kind == CommandKind::RestartApp {
+        restart_app(player).await
+    } else {
+        restart_device().await
+    };
+    // Still here, so if it failed nothing went down
+    if let Err(e) = result {
+        commands::clear_pending().await;
+        let _ = commands::report(api, &command, CommandReport::failed(e)).await;
+        if command.source == CommandSource::LegacyFlag {
+            seen.forget(command.id);
         }
     }
 }
 
-async fn process_schedules(
-    client: &Client,
-    config: &Config,
-    schedules: Vec<ClientPlaylistSchedule>
-) -> Result<(), Box<dyn Error>> {
-    let now = Utc::now();
-
-    // Load existing data.json
-    let mut data = Data::new();
-    if let Err(e) = data.load().await {
-        eprintln!("Failed to load data.json: {}", e);
-        return Err(e);
-    }
-
-    // Check the currently stored playlist
-    let existing_playlist = data.current_playlist;
-
-    for schedule in &schedules {
-        if now >= schedule.start_time && now <= schedule.end_time {
-            // If playlist hasn't changed, do nothing
-            if existing_playlist == Some(schedule.playlist_id) {
-                return Ok(());
-            }
-
-            // Otherwise, update current_playlist and last_update
-            data.current_playlist = Some(schedule.playlist_id);
-            data.update_content = Some(true);
-            // Update playlist ID in backend
-            if let Err(e) = update_playlist_id(client, config, schedule.playlist_id).await {
-                eprintln!("Error updating playlist ID: {}", e);
-            }
+/// Runs a `player` command against the mpv IPC socket
+async fn control_player(
+    player: &Player,
+    parameters: &serde_json::Value,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let action: PlayerAction = serde_json::from_value(parameters.clone())
+        .map_err(|e| format!("Invalid player command: {}", e))?;
+    Ok(player.ipc().control(action).await?)
+}
 
-            // Preserve `videos` and write updated data.json
-            if let Err(e) = data.write().await {
-                eprintln!("Failed to write data.json: {}", e);
-                return Err(e);
+/// Polls `/health` with jittered exponential backoff until the backend answers
+async fn wait_for_api(api: &SignageApi) {
+    let mut attempt = 0;
+    loop {
+        match api.health().await {
+            Ok(()) => return,
+            Err(e) => {
+                let delay = api.retry_policy().delay(attempt, &e);
+                println!("API not available ({}). Retrying in {:?}...", e, delay);
+                time::sleep(delay).await;
+                attempt = attempt.saturating_add(1);
             }
-            println!("Schedule updated - restarting OMNIPLAYER");
         }
     }
-    Ok(())
 }
 
-
-async fn update_playlist_id(
-    client: &Client,
-    config: &Config,
-    playlist_id: Uuid,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let url = format!("{}/update-client-playlist/{}", config.url, config.id);
-
-    let response = client
-        .post(&url)
-        .header("APIKEY", config.key.clone().unwrap_or_default())
-        .json(&serde_json::json!({ "playlist_id": playlist_id }))
-        .send()
-        .await?;
-
-    if response.status().is_success() {
-        Ok(())
+async fn restart_app(player: &Player) -> Result<(), Box<dyn Error>> {
+    println!("Restarting Signage Application...");
+    count(&COUNTERS.mpv_restarts);
+    if player.enabled() {
+        match player.stop().await {
+            Ok(()) => println!("MPV player stopped successfully."),
+            Err(e) => eprintln!("Failed to stop MPV player: {}", e),
+        }
     } else {
-        Err(format!("Failed to update playlist ID: {:?}", response.status()).into())
+        // Not our process to stop politely
+        match Command::new("pkill").arg("mpv").output().await {
+            Ok(output) if output.status.success() => println!("MPV player stopped successfully."),
+            Ok(output) => eprintln!(
+                "Failed to stop MPV player: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            Err(e) => eprintln!("Failed to execute stop MPV command: {}", e),
+        }
     }
-}
-
 
-async fn update_restart_app_flag(
-    client: &Client,
-    config: &Config,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let url = format!("{}/update-restart-app-device/{}", config.url, config.id);
-    let response = client
-        .post(&url)
-        .header("APIKEY", config.key.clone().unwrap_or_default())
-        .json(&serde_json::json!({ "restart_app": false }))
-        .send()
-        .await?;
-
-    if response.status().is_success() {
-        Ok(())
-    } else {
-        Err(format!("Failed to update restart flag: {:?}", response.status()).into())
-    }
+    restart_service().await
 }
 
-
-async fn restart_device(client: &Client, config: &Config) {
-    // Update the restart flag to false
-    let update_result = update_restart_flag(client, config).await;
-
-    if let Err(e) = update_result {
-        println!("Failed to update restart flag: {}", e);
-        return;
-    }
-
+async fn restart_device() -> Result<(), Box<dyn Error>> {
     println!("Restarting device...");
     let status = Command::new("sudo").arg("reboot").status().await;
 
     match status {
-        Ok(status) if status.success() => println!("Device is restarting..."),
-        Ok(status) => println!("Failed to restart device, exit code: {}", status),
-        Err(e) => println!("Failed to execute reboot command: {}", e),
-    }
-}
-
-async fn update_restart_flag(
-    client: &Client,
-    config: &Config,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let url = format!("{}/update-restart-device/{}", config.url, config.id);
-    let response = client
-        .post(&url)
-        .header("APIKEY", config.key.clone().unwrap_or_default())
-        .json(&serde_json::json!({ "restart": false }))
-        .send()
-        .await?;
-
-    if response.status().is_success() {
-        Ok(())
-    } else {
-        Err(format!("Failed to update restart flag: {:?}", response.status()).into())
+        Ok(status) if status.success() => {
+            println!("Device is restarting...");
+            Ok(())
+        }
+        Ok(status) => Err(format!("Failed to restart device, exit code: {}", status).into()),
+        Err(e) => Err(format!("Failed to execute reboot command: {}", e).into()),
     }
 }
 
-async fn take_screenshot(client: &Client, config: &Config) -> Result<(), Box<dyn Error>> {
+async fn take_screenshot(api: &SignageApi) -> Result<(), Box<dyn Error>> {
     println!("Taking screenshot");
     env::set_var("DISPLAY", ":0");
     env::set_var("XDG_RUNTIME_DIR", "/run/user/1000");
@@ -349,7 +378,7 @@ async fn take_screenshot(client: &Client, config: &Config) -> Result<(), Box<dyn
         .arg("--current")
         .output()
         .await
-        .expect("Failed to execute xrandr");
+        .map_err(|e| format!("Failed to execute xrandr: {}", e))?;
 
     let resolution_str = std::str::from_utf8(&resolution_output.stdout)?;
     let resolution_line = resolution_str
@@ -359,7 +388,7 @@ async fn take_screenshot(client: &Client, config: &Config) -> Result<(), Box<dyn
 
     let resolution = resolution_line
         .split_whitespace()
-        .nth(0)
+        .next()
         .ok_or("Failed to parse resolution")?;
 
     // Use the resolution in the ffmpeg command with temp file
@@ -381,76 +410,34 @@ async fn take_screenshot(client: &Client, config: &Config) -> Result<(), Box<dyn
         // Rename temp file to final file (atomic operation)
         std::fs::rename(&temp_screenshot_path, final_screenshot_path)?;
         println!("Screenshot saved");
-        
+
         // Call the upload_screenshot function after taking the screenshot
-        if let Err(e) = upload_screenshot(client, config, final_screenshot_path).await {
-            eprintln!("Failed to upload screenshot: {}", e);
-        }
+        upload_screenshot(api, final_screenshot_path)
+            .await
+            .map_err(|e| format!("Failed to upload screenshot: {}", e).into())
     } else {
-        eprintln!(
+        Err(format!(
             "Failed to take screenshot: {}",
             String::from_utf8_lossy(&output.stderr)
-        );
+        )
+        .into())
     }
-
-    Ok(())
 }
 
-async fn update_screenshot_flag(client: &Client, config: &Config) -> Result<(), Box<dyn Error>> {
-    let url = format!("{}/update-screenshot-device/{}", config.url, config.id);
-    let response = client
-        .post(&url)
-        .header("APIKEY", config.key.clone().unwrap_or_default())
-        .json(&json!({ "screenshot": false }))
-        .send()
-        .await?;
-
-    if response.status().is_success() {
-        Ok(())
-    } else {
-        Err(format!("Failed to update screenshot flag: {:?}", response.status()).into())
-    }
-}
-
-async fn upload_screenshot(
-    client: &Client,
-    config: &Config,
-    screenshot_path: &str,
-) -> Result<(), Box<dyn Error>> {
-    let url = format!("{}/upload-screenshot/{}", config.url, config.id);
+async fn upload_screenshot(api: &SignageApi, screenshot_path: &str) -> Result<(), Box<dyn Error>> {
     let mut file = File::open(screenshot_path)?;
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer)?;
 
-    let part = Part::bytes(buffer)
-        .file_name("screenshot.png")
-        .mime_str("image/png")?;
-
-    let form = Form::new().part("file", part);
-
-    let response = client
-        .post(&url)
-        .header("APIKEY", config.key.clone().unwrap_or_default())
-        .multipart(form)
-        .send()
-        .await?;
-
-    if response.status().is_success() {
-        println!("Screenshot uploaded");
+    api.upload_screenshot(buffer).await?;
+    println!("Screenshot uploaded");
 
-        // Delete the screenshot file from the device
-        if let Err(e) = std::fs::remove_file(screenshot_path) {
-            eprintln!("Failed to delete screenshot: {}", e);
-        } else {
-            println!("Screenshot completed");
-        }
-
-        if let Err(e) = update_screenshot_flag(client, config).await {
-            eprintln!("Failed to update screenshot flag: {}", e);
-        }
-
-        Ok(())
+    // Delete the screenshot file from the device
+    if let Err(e) = std::fs::remove_file(screenshot_path) {
+        eprintln!("Failed to delete screenshot: {}", e);
     } else {
-        Err(format!("Failed to upload screenshot: {:?}", response.status()).into())
+        println!("Screenshot completed");
     }
-}
\ No newline at end of file
+
+    Ok(())
+}
@@ -0,0 +1,561 @@
+use crate::util::data_dir;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::{boxed::Box, error::Error};
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::UnixStream;
+use tokio::process::{Child, Command};
+use tokio::sync::Mutex;
+use tokio::time::{self, Duration, Instant};
+
+/// How long one IPC request may take before mpv is considered unresponsive
+const IPC_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a freshly launched mpv has to open its IPC socket
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Request ids only have to be unique per connection, but a global counter is simplest
+static REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Settings under `player` in signage.json
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct PlayerConfig {
+    /// Launch and control mpv from the daemon; turn off when something else runs the player
+    pub enabled: bool,
+    /// The mpv binary
+    pub command: String,
+    /// Extra mpv arguments; `--idle`, `--input-ipc-server` and the playlist are always added
+    pub args: Vec<String>,
+    /// IPC socket path; `mpv.sock` in the data directory when unset
+    pub ipc_socket: Option<String>,
+    /// How often the watchdog checks on the player
+    pub check_interval_secs: u64,
+    /// How long playback may stand still, or the player go unanswered, before it is restarted
+    pub stall_timeout_secs: u64,
+    /// Restarts in a row that may fail to bring playback back before the whole service is
+    /// restarted instead
+    pub max_restarts: u32,
+}
+
+impl Default for PlayerConfig {
+    fn default() -> Self {
+        PlayerConfig {
+            enabled: false,
+            command: "mpv".to_string(),
+            args: vec!["--fs".to_string(), "--loop-playlist=inf".to_string()],
+            ipc_socket: None,
+            check_interval_secs: 5,
+            stall_timeout_secs: 30,
+            max_restarts: 5,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PlayerError {
+    /// The socket could not be reached or the connection failed
+    Io(std::io::Error),
+    /// mpv did not answer within `IPC_TIMEOUT`
+    Timeout,
+    /// mpv closed the connection without answering
+    Closed,
+    /// mpv answered with something other than JSON
+    Decode(serde_json::Error),
+    /// mpv rejected the command, e.g. "property unavailable"
+    Mpv(String),
+}
+
+impl fmt::Display for PlayerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlayerError::Io(e) => write!(f, "IPC error: {}", e),
+            PlayerError::Timeout => write!(f, "mpv did not answer in time"),
+            PlayerError::Closed => write!(f, "mpv closed the IPC connection"),
+            PlayerError::Decode(e) => write!(f, "failed to decode mpv reply: {}", e),
+            PlayerError::Mpv(e) => write!(f, "mpv error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PlayerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PlayerError::Io(e) => Some(e),
+            PlayerError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PlayerError {
+    fn from(e: std::io::Error) -> Self {
+        PlayerError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for PlayerError {
+    fn from(e: serde_json::Error) -> Self {
+        PlayerError::Decode(e)
+    }
+}
+
+/// One line of mpv output; events carry no `request_id`
+#[derive(Debug, Deserialize)]
+struct Reply {
+    request_id: Option<u64>,
+    error: Option<String>,
+    #[serde(default)]
+    data: Value,
+}
+
+/// Something mpv broadcast, such as `file-loaded` or `end-file`
+#[derive(Debug, Deserialize)]
+pub struct Event {
+    pub event: String,
+    /// Why a file ended, for `end-file`: `eof`, `stop`, `quit`, `error`, ...
+    pub reason: Option<String>,
+}
+
+/// A connection that receives every event mpv broadcasts, from `MpvIpc::subscribe`
+pub struct Events {
+    lines: Lines<BufReader<UnixStream>>,
+}
+
+impl Events {
+    /// The next event, or `None` once mpv has closed the connection
+    pub async fn next(&mut self) -> Result<Option<Event>, PlayerError> {
+        while let Some(line) = self.lines.next_line().await? {
+            let value: Value = serde_json::from_str(&line)?;
+            if value.get("event").is_some() {
+                return Ok(Some(serde_json::from_value(value)?));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// What the player is doing right now
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct PlaybackStatus {
+    /// File being played; `None` while idle
+    pub path: Option<String>,
+    /// Seconds into the file
+    pub position: Option<f64>,
+    pub paused: bool,
+}
+
+/// Parameters of a `player` command, e.g. `{"action": "seek", "seconds": 30}`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum PlayerAction {
+    Next,
+    Previous,
+    Seek {
+        seconds: f64,
+        #[serde(default)]
+        absolute: bool,
+    },
+    Volume {
+        volume: u8,
+    },
+    Pause,
+    Resume,
+    /// Reports the `PlaybackStatus` as the command's output
+    Status,
+}
+
+/// A client for mpv's JSON IPC protocol on a Unix socket.
+///
+/// Every request opens its own connection, so a restarted player is picked up without any
+/// reconnect logic and anything that answers the protocol on the socket will do.
+#[derive(Debug, Clone)]
+pub struct MpvIpc {
+    socket: String,
+}
+
+impl MpvIpc {
+    pub fn new(socket: impl Into<String>) -> Self {
+        MpvIpc {
+            socket: socket.into(),
+        }
+    }
+
+    /// Sends `command`, e.g. `["playlist-next"]`, and returns the `data` of the reply
+    pub async fn command(&self, command: Value) -> Result<Value, PlayerError> {
+        let request_id = REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+        let mut request = serde_json::to_vec(&json!({
+            "command": command,
+            "request_id": request_id,
+        }))?;
+        request.push(b'\n');
+
+        time::timeout(IPC_TIMEOUT, async {
+            let stream = UnixStream::connect(&self.socket).await?;
+            let (read, mut write) = stream.into_split();
+            write.write_all(&request).await?;
+
+            let mut lines = BufReader::new(read).lines();
+            while let Some(line) = lines.next_line().await? {
+                let reply: Reply = serde_json::from_str(&line)?;
+                if reply.request_id != Some(request_id) {
+                    continue;
+                }
+                return match reply.error.as_deref() {
+                    Some("success") | None => Ok(reply.data),
+                    Some(error) => Err(PlayerError::Mpv(error.to_string())),
+                };
+            }
+            Err(PlayerError::Closed)
+        })
+        .await
+        .map_err(|_| PlayerError::Timeout)?
+    }
+
+    /// Opens a connection of its own for mpv's events
+    pub async fn subscribe(&self) -> Result<Events, PlayerError> {
+        let stream = UnixStream::connect(&self.socket).await?;
+        Ok(Events {
+            lines: BufReader::new(stream).lines(),
+        })
+    }
+
+    /// Reads a property, giving `None` when mpv has no value for it (e.g. `time-pos` while idle)
+    pub async fn get_property<T: DeserializeOwned>(
+        &self,
+        name: &str,
+    ) -> Result<Option<T>, PlayerError> {
+        match self.command(json!(["get_property", name])).await {
+            Ok(Value::Null) => Ok(None),
+            Ok(value) => Ok(Some(serde_json::from_value(value)?)),
+            Err(PlayerError::Mpv(e)) if e == "property unavailable" => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn set_property(&self, name: &str, value: Value) -> Result<(), PlayerError> {
+        self.command(json!(["set_property", name, value]))
+            .await
+            .map(|_| ())
+    }
+
+    /// Replaces the playlist with the files listed in `path` and starts playing it
+    pub async fn load_playlist(&self, path: &str) -> Result<(), PlayerError> {
+        self.command(json!(["loadlist", path, "replace"]))
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn next(&self) -> Result<(), PlayerError> {
+        self.command(json!(["playlist-next", "force"]))
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn previous(&self) -> Result<(), PlayerError> {
+        self.command(json!(["playlist-prev", "force"]))
+            .await
+            .map(|_| ())
+    }
+
+    /// Seeks to `seconds` into the file, or by `seconds` from the current position when not
+    /// `absolute`
+    pub async fn seek(&self, seconds: f64, absolute: bool) -> Result<(), PlayerError> {
+        let mode = if absolute { "absolute" } else { "relative" };
+        self.command(json!(["seek", seconds, mode]))
+            .await
+            .map(|_| ())
+    }
+
+    /// Sets the volume, 0 to 100
+    pub async fn set_volume(&self, volume: u8) -> Result<(), PlayerError> {
+        self.set_property("volume", json!(volume.min(100))).await
+    }
+
+    pub async fn set_pause(&self, paused: bool) -> Result<(), PlayerError> {
+        self.set_property("pause", json!(paused)).await
+    }
+
+    pub async fn current_file(&self) -> Result<Option<String>, PlayerError> {
+        self.get_property("path").await
+    }
+
+    pub async fn position(&self) -> Result<Option<f64>, PlayerError> {
+        self.get_property("time-pos").await
+    }
+
+    pub async fn status(&self) -> Result<PlaybackStatus, PlayerError> {
+        Ok(PlaybackStatus {
+            path: self.current_file().await?,
+            position: self.position().await?,
+            paused: self.get_property("pause").await?.unwrap_or(false),
+        })
+    }
+
+    /// Asks mpv to exit
+    pub async fn quit(&self) -> Result<(), PlayerError> {
+        self.command(json!(["quit"])).await.map(|_| ())
+    }
+
+    /// Carries out a remote `player` command, returning any output for its report
+    pub async fn control(&self, action: PlayerAction) -> Result<Option<String>, PlayerError> {
+        match action {
+            PlayerAction::Next => self.next().await?,
+            PlayerAction::Previous => self.previous().await?,
+            PlayerAction::Seek { seconds, absolute } => self.seek(seconds, absolute).await?,
+            PlayerAction::Volume { volume } => self.set_volume(volume).await?,
+            PlayerAction::Pause => self.set_pause(true).await?,
+            PlayerAction::Resume => self.set_pause(false).await?,
+            PlayerAction::Status => {
+                return Ok(Some(serde_json::to_string(&self.status().await?)?));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The mpv process the daemon runs and controls over IPC. Cheap to clone; clones share the
+/// process.
+#[derive(Clone)]
+pub struct Player {
+    config: PlayerConfig,
+    ipc: MpvIpc,
+    child: Arc<Mutex<Option<Child>>>,
+    /// Clockwise degrees the video is turned, kept so a relaunched player comes back turned
+    rotation: Arc<AtomicU32>,
+}
+
+impl Player {
+    pub fn new(config: &PlayerConfig) -> Result<Self, Box<dyn Error>> {
+        let socket = match &config.ipc_socket {
+            Some(socket) => socket.clone(),
+            None => format!("{}/mpv.sock", data_dir()?),
+        };
+        Ok(Player {
+            config: config.clone(),
+            ipc: MpvIpc::new(socket),
+            child: Arc::default(),
+            rotation: Arc::default(),
+        })
+    }
+
+    /// Whether the daemon runs the player itself
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn ipc(&self) -> &MpvIpc {
+        &self.ipc
+    }
+
+    /// Starts mpv idle, or playing `playlist` if it exists, and waits for its IPC socket
+    pub async fn launch(&self, playlist: &str) -> Result<(), Box<dyn Error>> {
+        let mut child = self.child.lock().await;
+        if let Some(running) = child.as_mut() {
+            if running.try_wait()?.is_none() {
+                return Ok(());
+            }
+        }
+
+        // A socket left behind by a previous mpv would make us think it is already up
+        let _ = fs::remove_file(&self.ipc.socket).await;
+
+        let mut command = Command::new(&self.config.command);
+        command
+            .args(&self.config.args)
+            .arg("--idle=yes")
+            .arg("--no-terminal")
+            .arg(format!("--input-ipc-server={}", self.ipc.socket))
+            .arg(format!(
+                "--video-rotate={}",
+                self.rotation.load(Ordering::Relaxed)
+            ))
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        if Path::new(playlist).exists() {
+            command.arg(format!("--playlist={}", playlist));
+        }
+        *child = Some(command.spawn()?);
+        drop(child);
+
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+        loop {
+            match self.ipc.get_property::<String>("mpv-version").await {
+                Ok(version) => {
+                    println!("Started {}", version.unwrap_or_else(|| "mpv".to_string()));
+                    return Ok(());
+                }
+                Err(e) if Instant::now() >= deadline => {
+                    return Err(format!("mpv did not open its IPC socket: {}", e).into())
+                }
+                Err(_) => time::sleep(Duration::from_millis(200)).await,
+            }
+        }
+    }
+
+    /// Turns the video `degrees` clockwise, now and whenever the player is relaunched
+    pub async fn set_rotation(&self, degrees: u32) -> Result<(), PlayerError> {
+        self.rotation.store(degrees, Ordering::Relaxed);
+        self.ipc.set_property("video-rotate", json!(degrees)).await
+    }
+
+    /// Whether the process we started has gone away, or was never started
+    pub async fn has_exited(&self) -> bool {
+        match self.child.lock().await.as_mut() {
+            Some(child) => !matches!(child.try_wait(), Ok(None)),
+            None => true,
+        }
+    }
+
+    /// Stops whatever is left of the player and launches a fresh one
+    pub async fn restart(&self, playlist: &str) -> Result<(), Box<dyn Error>> {
+        self.stop().await?;
+        self.launch(playlist).await
+    }
+
+    /// Whether a player answers on the IPC socket
+    pub async fn is_running(&self) -> bool {
+        self.ipc.get_property::<i64>("pid").await.is_ok()
+    }
+
+    /// Stops the player: politely over IPC, then by killing the process we started
+    pub async fn stop(&self) -> Result<(), Box<dyn Error>> {
+        if let Err(e) = self.ipc.quit().await {
+            eprintln!("Failed to ask mpv to quit: {}", e);
+        }
+
+        let mut child = self.child.lock().await;
+        if let Some(mut running) = child.take() {
+            match time::timeout(IPC_TIMEOUT, running.wait()).await {
+                Ok(status) => {
+                    status?;
+                }
+                Err(_) => {
+                    running.kill().await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixListener;
+
+    /// A fake mpv listening in a fresh directory. Each connection reads one request and gets
+    /// `replies`, with `{id}` standing in for the request's id.
+    fn fake_mpv(replies: &[&str]) -> (tempfile::TempDir, MpvIpc) {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("mpv.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let replies: Vec<String> = replies.iter().map(|reply| reply.to_string()).collect();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (read, mut write) = stream.into_split();
+                let request = BufReader::new(read)
+                    .lines()
+                    .next_line()
+                    .await
+                    .unwrap()
+                    .unwrap();
+                let request: Value = serde_json::from_str(&request).unwrap();
+                let id = request["request_id"].to_string();
+                for reply in &replies {
+                    let line = format!("{}\n", reply.replace("{id}", &id));
+                    write.write_all(line.as_bytes()).await.unwrap();
+                }
+            }
+        });
+        (dir, MpvIpc::new(socket.to_str().unwrap()))
+    }
+
+    #[tokio::test]
+    async fn replies_are_matched_by_request_id() {
+        let (_dir, ipc) = fake_mpv(&[
+            r#"{"event":"file-loaded"}"#,
+            r#"{"request_id":0,"error":"success","data":"someone else's"}"#,
+            r#"{"request_id":{id},"error":"success","data":"/media/a.mp4"}"#,
+        ]);
+        assert_eq!(
+            ipc.current_file().await.unwrap().as_deref(),
+            Some("/media/a.mp4")
+        );
+    }
+
+    #[tokio::test]
+    async fn error_replies_become_errors() {
+        let (_dir, ipc) = fake_mpv(&[r#"{"request_id":{id},"error":"invalid parameter"}"#]);
+        match ipc.next().await {
+            Err(PlayerError::Mpv(e)) => assert_eq!(e, "invalid parameter"),
+            other => panic!("expected an mpv error, got {:?}", other),
+        }
+
+        // Except for properties mpv has no value for
+        let (_dir, ipc) = fake_mpv(&[r#"{"request_id":{id},"error":"property unavailable"}"#]);
+        assert_eq!(ipc.position().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn a_connection_closed_without_a_reply_is_an_error() {
+        let (_dir, ipc) = fake_mpv(&[r#"{"event":"idle"}"#]);
+        assert!(matches!(ipc.quit().await, Err(PlayerError::Closed)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_silent_player_times_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("mpv.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let _server = tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await
+        });
+
+        let ipc = MpvIpc::new(socket.to_str().unwrap());
+        assert!(matches!(ipc.next().await, Err(PlayerError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn subscribe_streams_events_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("mpv.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let lines = [
+                r#"{"event":"file-loaded"}"#,
+                r#"{"request_id":7,"error":"success","data":null}"#,
+                r#"{"event":"end-file","reason":"eof"}"#,
+            ];
+            for line in lines {
+                stream
+                    .write_all(format!("{}\n", line).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let mut events = MpvIpc::new(socket.to_str().unwrap())
+            .subscribe()
+            .await
+            .unwrap();
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!((event.event.as_str(), event.reason), ("file-loaded", None));
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(
+            (event.event.as_str(), event.reason.as_deref()),
+            ("end-file", Some("eof"))
+        );
+        // The player went away
+        assert!(events.next().await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,198 @@
+use crate::api::SignageApi;
+use crate::buffer::DiskQueue;
+use crate::config::Config;
+use crate::data::Data;
+use crate::player::{Event, Player};
+use crate::util::data_dir;
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use std::{boxed::Box, error::Error};
+use tokio::sync::{watch, Mutex};
+use tokio::time::{self, Duration, MissedTickBehavior};
+use uuid::Uuid;
+
+/// How long to wait before listening to the player again after losing it
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+/// Settings under `proof_of_play` in signage.json
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ProofOfPlayConfig {
+    pub enabled: bool,
+    /// How often the log is uploaded
+    pub upload_interval_secs: u64,
+    /// Records per upload request
+    pub batch_size: usize,
+    /// Most records kept on disk while the backend is unreachable
+    pub buffer_max_records: usize,
+    /// Most bytes kept on disk while the backend is unreachable
+    pub buffer_max_bytes: u64,
+}
+
+impl Default for ProofOfPlayConfig {
+    fn default() -> Self {
+        ProofOfPlayConfig {
+            enabled: true,
+            upload_interval_secs: 60,
+            batch_size: 200,
+            buffer_max_records: 100_000,
+            buffer_max_bytes: 32 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlayOutcome {
+    /// Played to the end
+    Completed,
+    /// Cut short by a playlist change, skip, player restart or error
+    Interrupted,
+}
+
+/// One play of one asset
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PlayRecord {
+    /// Generated on the device so the backend can drop records it already has after a retry
+    pub id: Uuid,
+    pub asset_id: String,
+    pub playlist_id: Option<Uuid>,
+    /// `None` while the fallback playlist is playing
+    pub schedule_id: Option<Uuid>,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub outcome: PlayOutcome,
+}
+
+/// An asset that has started but not yet ended
+struct Playing {
+    asset_id: String,
+    playlist_id: Option<Uuid>,
+    schedule_id: Option<Uuid>,
+    started_at: DateTime<Utc>,
+}
+
+impl Playing {
+    fn end(self, outcome: PlayOutcome) -> PlayRecord {
+        PlayRecord {
+            id: Uuid::new_v4(),
+            asset_id: self.asset_id,
+            playlist_id: self.playlist_id,
+            schedule_id: self.schedule_id,
+            started_at: self.started_at,
+            ended_at: Utc::now(),
+            outcome,
+        }
+    }
+}
+
+/// The log doubles as the upload backlog: records leave it only once the backend has them
+type Log = Arc<Mutex<DiskQueue<PlayRecord>>>;
+
+async fn append(log: &Log, record: PlayRecord) {
+    if let Err(e) = log.lock().await.push(&record).await {
+        eprintln!("Failed to record play of {}: {}", record.asset_id, e);
+    }
+}
+
+/// The asset now playing and what put it on screen. Media is stored as `{asset_id}.{ext}`.
+async fn now_playing(player: &Player) -> Option<Playing> {
+    let path = player.ipc().current_file().await.ok()??;
+    let asset_id = Path::new(&path).file_stem()?.to_str()?.to_string();
+
+    let data = Data::read().await.unwrap_or_default();
+    Some(Playing {
+        asset_id,
+        playlist_id: data.current_playlist,
+        schedule_id: data.current_schedule,
+        started_at: Utc::now(),
+    })
+}
+
+/// Follows the player's `file-loaded` and `end-file` events, logging a record per play.
+/// A file that ends other than by reaching its end, or is still playing when the player goes
+/// away, counts as interrupted.
+async fn record(player: Player, log: Log) {
+    loop {
+        if let Ok(mut events) = player.ipc().subscribe().await {
+            let mut playing: Option<Playing> = None;
+            while let Ok(Some(Event { event, reason })) = events.next().await {
+                match event.as_str() {
+                    "file-loaded" => {
+                        if let Some(previous) = playing.take() {
+                            append(&log, previous.end(PlayOutcome::Interrupted)).await;
+                        }
+                        playing = now_playing(&player).await;
+                    }
+                    "end-file" => {
+                        let outcome = match reason.as_deref() {
+                            Some("eof") => PlayOutcome::Completed,
+                            _ => PlayOutcome::Interrupted,
+                        };
+                        if let Some(current) = playing.take() {
+                            append(&log, current.end(outcome)).await;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            if let Some(current) = playing.take() {
+                append(&log, current.end(PlayOutcome::Interrupted)).await;
+            }
+        }
+        time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Uploads the log oldest first until it is empty or a batch fails.
+///
+/// The log is only locked to read and trim it, so plays keep being recorded on time while a
+/// batch is on its way.
+async fn flush(api: &SignageApi, log: &Log, batch_size: usize) -> Result<(), Box<dyn Error>> {
+    loop {
+        let batch = log.lock().await.peek(batch_size.max(1)).await?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        if !batch.records.is_empty() {
+            api.send_proof_of_play(&batch.records).await?;
+            println!("Uploaded {} proof-of-play record(s)", batch.records.len());
+        }
+        log.lock().await.remove(&batch).await?;
+    }
+}
+
+/// Records what the player shows in `proof_of_play.jsonl` and uploads it in batches
+pub async fn run(client: Client, config: watch::Receiver<Config>, player: Player) {
+    let settings = config.borrow().proof_of_play.clone();
+    let path = match data_dir() {
+        Ok(dir) => format!("{}/proof_of_play.jsonl", dir),
+        Err(e) => {
+            eprintln!("Proof of play is off: {}", e);
+            return;
+        }
+    };
+    let log: Log = Arc::new(Mutex::new(DiskQueue::new(
+        path,
+        settings.buffer_max_records,
+        settings.buffer_max_bytes,
+    )));
+    tokio::spawn(record(player, log.clone()));
+
+    let mut interval = time::interval(Duration::from_secs(settings.upload_interval_secs.max(1)));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let api = SignageApi::new(client.clone(), &config.borrow());
+        if let Err(e) = flush(&api, &log, settings.batch_size)
+            .await
+            .map_err(|e| e.to_string())
+        {
+            eprintln!("Failed to upload proof of play: {}", e);
+        }
+    }
+}
@@ -0,0 +1,412 @@
+use chrono::{
+    DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday,
+};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::{env, fs};
+
+const WEEKDAYS: [Weekday; 5] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+];
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Frequency {
+    Daily,
+    Weekdays,
+}
+
+/// Which days a recurring schedule plays on: `"daily"`, `"weekdays"` or a list such as
+/// `["sat", "sun"]`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Days {
+    Every(Frequency),
+    On(Vec<Weekday>),
+}
+
+impl Default for Days {
+    fn default() -> Self {
+        Days::Every(Frequency::Daily)
+    }
+}
+
+impl Days {
+    pub fn contains(&self, day: Weekday) -> bool {
+        match self {
+            Days::Every(Frequency::Daily) => true,
+            Days::Every(Frequency::Weekdays) => WEEKDAYS.contains(&day),
+            Days::On(days) => days.contains(&day),
+        }
+    }
+}
+
+/// A daypart repeating inside a schedule's `start_time`..`end_time` range, in the device's
+/// local time.
+///
+/// `rrule` takes precedence over `days` and supports `FREQ=DAILY|WEEKLY`, `INTERVAL`, `BYDAY`
+/// (plain two-letter days) and `UNTIL`. A weekly rule without `BYDAY` repeats on the weekday
+/// the schedule starts. An `end` at or before `start` runs past midnight.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Recurrence {
+    #[serde(default)]
+    pub days: Days,
+    pub rrule: Option<String>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+/// The last start an `UNTIL` allows
+#[derive(Debug, PartialEq)]
+enum Until {
+    /// `YYYYMMDD`: anything starting on that local day or before
+    Date(NaiveDate),
+    /// `YYYYMMDDTHHMMSS`, in local time
+    Local(NaiveDateTime),
+    /// `YYYYMMDDTHHMMSSZ`
+    Utc(DateTime<Utc>),
+}
+
+impl Until {
+    fn parse(value: &str) -> Option<Self> {
+        if let Some(utc) = value.strip_suffix(['Z', 'z']) {
+            let utc = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").ok()?;
+            Some(Until::Utc(Utc.from_utc_datetime(&utc)))
+        } else if value.contains(['T', 't']) {
+            NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")
+                .ok()
+                .map(Until::Local)
+        } else {
+            NaiveDate::parse_from_str(value, "%Y%m%d")
+                .ok()
+                .map(Until::Date)
+        }
+    }
+
+    /// Whether an occurrence starting at `local` wall-clock time, `instant` in UTC, is allowed
+    fn allows(&self, local: NaiveDateTime, instant: DateTime<Utc>) -> bool {
+        match self {
+            Until::Date(until) => local.date() <= *until,
+            Until::Local(until) => local <= *until,
+            Until::Utc(until) => instant <= *until,
+        }
+    }
+}
+
+/// `days` or `rrule`, normalised
+struct Rule {
+    weekly: bool,
+    interval: i64,
+    /// `None` means every day, or for weekly rules the anchor's weekday
+    days: Option<Vec<Weekday>>,
+    until: Option<Until>,
+}
+
+impl Rule {
+    fn from_days(days: &Days) -> Self {
+        let days = match days {
+            Days::Every(Frequency::Daily) => None,
+            Days::Every(Frequency::Weekdays) => Some(WEEKDAYS.to_vec()),
+            Days::On(days) => Some(days.clone()),
+        };
+        Rule {
+            weekly: false,
+            interval: 1,
+            days,
+            until: None,
+        }
+    }
+
+    fn parse(rrule: &str) -> Result<Self, String> {
+        let rrule = rrule.trim();
+        let rrule = rrule.strip_prefix("RRULE:").unwrap_or(rrule);
+
+        let mut weekly = None;
+        let mut rule = Rule {
+            weekly: false,
+            interval: 1,
+            days: None,
+            until: None,
+        };
+        for part in rrule.split(';').filter(|part| !part.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("malformed RRULE part {:?}", part))?;
+            match key.to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    weekly = Some(match value.to_ascii_uppercase().as_str() {
+                        "DAILY" => false,
+                        "WEEKLY" => true,
+                        _ => return Err(format!("unsupported RRULE frequency {:?}", value)),
+                    })
+                }
+                "INTERVAL" => {
+                    rule.interval = value
+                        .parse()
+                        .ok()
+                        .filter(|interval| *interval > 0)
+                        .ok_or_else(|| format!("invalid RRULE interval {:?}", value))?
+                }
+                "BYDAY" => rule.days = Some(value.split(',').map(byday).collect::<Result<_, _>>()?),
+                "UNTIL" => {
+                    rule.until = Some(
+                        Until::parse(value)
+                            .ok_or_else(|| format!("invalid RRULE until {:?}", value))?,
+                    )
+                }
+                _ => return Err(format!("unsupported RRULE part {:?}", key)),
+            }
+        }
+
+        rule.weekly = weekly.ok_or("RRULE has no FREQ")?;
+        Ok(rule)
+    }
+
+    /// Whether an occurrence starts on `date`, counting intervals from `anchor`. `UNTIL` is
+    /// left to the caller, as it may need the time of day.
+    fn includes(&self, anchor: NaiveDate, date: NaiveDate) -> bool {
+        if date < anchor {
+            return false;
+        }
+        let on_day = match &self.days {
+            Some(days) => days.contains(&date.weekday()),
+            None => !self.weekly || date.weekday() == anchor.weekday(),
+        };
+        if !on_day {
+            return false;
+        }
+        let elapsed = if self.weekly {
+            (week_start(date) - week_start(anchor)).num_days() / 7
+        } else {
+            (date - anchor).num_days()
+        };
+        elapsed % self.interval == 0
+    }
+}
+
+fn byday(day: &str) -> Result<Weekday, String> {
+    match day.trim().to_ascii_uppercase().as_str() {
+        "MO" => Ok(Weekday::Mon),
+        "TU" => Ok(Weekday::Tue),
+        "WE" => Ok(Weekday::Wed),
+        "TH" => Ok(Weekday::Thu),
+        "FR" => Ok(Weekday::Fri),
+        "SA" => Ok(Weekday::Sat),
+        "SU" => Ok(Weekday::Sun),
+        _ => Err(format!("unsupported RRULE day {:?}", day)),
+    }
+}
+
+fn week_start(date: NaiveDate) -> NaiveDate {
+    date - Duration::days(date.weekday().num_days_from_monday().into())
+}
+
+/// The instant a local wall-clock time refers to.
+///
+/// Times repeated when clocks go back resolve to the first occurrence. Times skipped when
+/// clocks go forward resolve to the moment the clocks jump, so a 02:30 start still plays.
+fn local_instant(tz: &Tz, local: NaiveDateTime) -> DateTime<Utc> {
+    let mut candidate = local;
+    // The longest DST gap in the database is two hours; allow a whole day to be safe
+    for _ in 0..24 * 60 {
+        if let Some(instant) = tz.from_local_datetime(&candidate).earliest() {
+            return instant.with_timezone(&Utc);
+        }
+        candidate += Duration::minutes(1);
+    }
+    Utc.from_utc_datetime(&local)
+}
+
+impl Recurrence {
+    fn rule(&self) -> Result<Rule, String> {
+        match &self.rrule {
+            Some(rrule) => Rule::parse(rrule),
+            None => Ok(Rule::from_days(&self.days)),
+        }
+    }
+
+    /// Checks the rule can be evaluated; invalid rules never produce occurrences
+    pub fn validate(&self) -> Result<(), String> {
+        self.rule().map(|_| ())
+    }
+
+    /// The occurrence starting on local `date`, if the rule includes that day.
+    /// `anchor` is the local date intervals are counted from.
+    pub fn occurrence(
+        &self,
+        tz: &Tz,
+        anchor: NaiveDate,
+        date: NaiveDate,
+    ) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let rule = self.rule().ok()?;
+        if !rule.includes(anchor, date) {
+            return None;
+        }
+        let end_date = if self.end <= self.start {
+            date.succ_opt()?
+        } else {
+            date
+        };
+        let start = local_instant(tz, date.and_time(self.start));
+        if let Some(until) = &rule.until {
+            if !until.allows(date.and_time(self.start), start) {
+                return None;
+            }
+        }
+        let end = local_instant(tz, end_date.and_time(self.end));
+        (start < end).then_some((start, end))
+    }
+}
+
+/// The IANA zone recurring schedules are evaluated in: `configured` if set, then `$TZ`,
+/// then the system zone from /etc/timezone or the /etc/localtime link, else UTC
+pub fn device_timezone(configured: Option<&str>) -> Tz {
+    let system = || {
+        fs::read_to_string("/etc/timezone")
+            .ok()
+            .map(|zone| zone.trim().to_string())
+            .or_else(|| {
+                let link = fs::read_link("/etc/localtime").ok()?;
+                let link = link.to_str()?;
+                Some(link.split_once("zoneinfo/")?.1.to_string())
+            })
+    };
+
+    let name = configured
+        .map(str::to_string)
+        .or_else(|| {
+            env::var("TZ")
+                .ok()
+                .map(|tz| tz.trim_start_matches(':').to_string())
+        })
+        .or_else(system);
+
+    match name.as_deref().map(str::parse::<Tz>) {
+        Some(Ok(tz)) => tz,
+        Some(Err(e)) => {
+            eprintln!(
+                "Unknown timezone {:?} ({}), using UTC",
+                name.unwrap_or_default(),
+                e
+            );
+            Tz::UTC
+        }
+        None => Tz::UTC,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(date: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap()
+    }
+
+    /// A 20:00-22:00 daypart following `rrule`
+    fn recurrence(rrule: &str) -> Recurrence {
+        Recurrence {
+            days: Days::default(),
+            rrule: Some(rrule.to_string()),
+            start: NaiveTime::from_hms_opt(20, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+        }
+    }
+
+    /// Whether `rrule`, anchored on Monday 2026-01-05, plays on each date
+    fn check(tz: Tz, cases: &[(&str, &str, bool)]) {
+        for (rrule, day, expected) in cases {
+            let plays = recurrence(rrule)
+                .occurrence(&tz, date("2026-01-05"), date(day))
+                .is_some();
+            assert_eq!(plays, *expected, "{} on {}", rrule, day);
+        }
+    }
+
+    #[test]
+    fn interval_counts_from_the_anchor() {
+        check(
+            Tz::UTC,
+            &[
+                ("FREQ=DAILY;INTERVAL=2", "2026-01-04", false),
+                ("FREQ=DAILY;INTERVAL=2", "2026-01-05", true),
+                ("FREQ=DAILY;INTERVAL=2", "2026-01-06", false),
+                ("FREQ=DAILY;INTERVAL=2", "2026-01-07", true),
+                ("FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,WE", "2026-01-07", true),
+                ("FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,WE", "2026-01-12", false),
+                ("FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,WE", "2026-01-14", false),
+                ("FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,WE", "2026-01-19", true),
+            ],
+        );
+    }
+
+    #[test]
+    fn byday_picks_the_weekdays() {
+        check(
+            Tz::UTC,
+            &[
+                ("FREQ=DAILY;BYDAY=SA,SU", "2026-01-09", false),
+                ("FREQ=DAILY;BYDAY=SA,SU", "2026-01-10", true),
+                ("FREQ=DAILY;BYDAY=SA,SU", "2026-01-11", true),
+                ("RRULE:freq=weekly;byday=tu", "2026-01-06", true),
+                ("RRULE:freq=weekly;byday=tu", "2026-01-05", false),
+            ],
+        );
+    }
+
+    #[test]
+    fn weekly_without_byday_repeats_on_the_anchor_weekday() {
+        check(
+            Tz::UTC,
+            &[
+                ("FREQ=WEEKLY", "2026-01-05", true),
+                ("FREQ=WEEKLY", "2026-01-06", false),
+                ("FREQ=WEEKLY", "2026-01-11", false),
+                ("FREQ=WEEKLY", "2026-01-12", true),
+            ],
+        );
+    }
+
+    #[test]
+    fn until_is_inclusive_and_utc_values_are_compared_as_instants() {
+        // 20:00 in New York is 01:00 UTC the next day
+        let new_york: Tz = "America/New_York".parse().unwrap();
+        check(
+            new_york,
+            &[
+                ("FREQ=DAILY;UNTIL=20260110", "2026-01-10", true),
+                ("FREQ=DAILY;UNTIL=20260110", "2026-01-11", false),
+                ("FREQ=DAILY;UNTIL=20260110T200000", "2026-01-10", true),
+                ("FREQ=DAILY;UNTIL=20260110T195959", "2026-01-10", false),
+                ("FREQ=DAILY;UNTIL=20260111T010000Z", "2026-01-10", true),
+                // Still January 10th locally, even though the date in the rule is the 11th
+                ("FREQ=DAILY;UNTIL=20260111T010000Z", "2026-01-11", false),
+                ("FREQ=DAILY;UNTIL=20260111T005959Z", "2026-01-10", false),
+            ],
+        );
+    }
+
+    #[test]
+    fn unsupported_rules_are_rejected() {
+        let cases = [
+            "FREQ=DAILY;COUNT=3",
+            "FREQ=MONTHLY",
+            "FREQ=WEEKLY;BYDAY=1MO",
+            "FREQ=DAILY;INTERVAL=0",
+            "FREQ=DAILY;UNTIL=tomorrow",
+            "FREQ=DAILY;BYHOUR",
+            "INTERVAL=2",
+        ];
+        for rrule in cases {
+            assert!(recurrence(rrule).validate().is_err(), "{}", rrule);
+            // And never play
+            assert!(recurrence(rrule)
+                .occurrence(&Tz::UTC, date("2026-01-05"), date("2026-01-05"))
+                .is_none());
+        }
+    }
+}
@@ -1,67 +1,71 @@
-use crate::config::Config;
-use crate::util::run_command;
-use reqwest::blocking::Client;
-use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
-use serde::Serialize;
+use crate::api::SignageApi;
+use crate::buffer::DiskQueue;
+use crate::player::Player;
+use crate::sysinfo::SystemCollector;
+use crate::telemetry::{download_progress, schedule_source, DownloadProgress};
+use crate::util::{data_dir, run_command};
+use crate::VERSION;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
 use std::fs::File;
 use std::io::Write;
+use tokio::sync::watch;
+use tokio::time::{self, Duration, MissedTickBehavior};
 use uuid::Uuid;
 
-pub async fn temp() -> String {
-    run_command("sh", &["-c", "cat /sys/class/thermal/thermal_zone0/temp | column -s $'\\t' -t | sed 's/\\(.\\)..$/.\\1/'"]).await.unwrap_or_default()
-}
-
-async fn cpu_usage() -> String {
-    run_command("sh", &["-c", "top -bn1 | awk '/Cpu/ {print $2}'"])
-        .await
-        .unwrap_or_default()
-}
-
-async fn memory() -> String {
-    run_command(
-        "sh",
-        &[
-            "-c",
-            "free -h --si | awk '/Mem/ {printf \"%3.1f\", $3/$2*100}'",
-        ],
-    )
-    .await
-    .unwrap_or_default()
-}
+/// How often vitals are sampled, and so how often the main loop reports and polls
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(30);
 
-async fn disk_usage() -> String {
-    run_command("sh", &["-c", "df --output=pcent / | tr -dc '0-9'"])
-        .await
-        .unwrap_or_default()
+/// Settings under `metrics` in signage.json
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Timeout for a single upload attempt
+    pub timeout_secs: u64,
+    /// Payload shape; set to "legacy" for backends that only understand string vitals
+    pub schema: MetricsSchema,
+    /// Most samples kept on disk while the backend is unreachable
+    pub buffer_max_samples: usize,
+    /// Most bytes kept on disk while the backend is unreachable
+    pub buffer_max_bytes: u64,
+    /// Samples per request when uploading the backlog
+    pub batch_size: usize,
+    /// Address for the local Prometheus endpoint, e.g. "0.0.0.0:9100". Off when unset.
+    pub exporter_listen: Option<String>,
 }
 
-async fn swap_usage() -> String {
-    run_command(
-        "sh",
-        &[
-            "-c",
-            "free -h --si | awk '/Swap/ {printf \"%3.1f\", $3/$2*100}'",
-        ],
-    )
-    .await
-    .unwrap_or_default()
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            timeout_secs: 10,
+            schema: MetricsSchema::default(),
+            // A day of samples at one every 30 seconds
+            buffer_max_samples: 2880,
+            buffer_max_bytes: 4 * 1024 * 1024,
+            batch_size: 100,
+            exporter_listen: None,
+        }
+    }
 }
 
-async fn uptime() -> String {
-    run_command("sh", &["-c", "uptime | awk '{print $3}' | tr -d ','"])
-        .await
-        .unwrap_or_default()
+impl MetricsConfig {
+    /// On-disk backlog of samples that failed to send
+    fn buffer(&self) -> Result<DiskQueue<Metrics>, Box<dyn Error>> {
+        Ok(DiskQueue::new(
+            format!("{}/metrics_buffer.jsonl", data_dir()?),
+            self.buffer_max_samples,
+            self.buffer_max_bytes,
+        ))
+    }
 }
 
-async fn mpvstatus() -> String {
+/// Whether any mpv process is running, including one the daemon doesn't control
+async fn mpv_process_running() -> bool {
     let output = run_command("sh", &["-c", "ps aux | grep -v grep | grep mpv"])
         .await
         .unwrap_or_default();
-    if output.is_empty() {
-        "not running".to_string()
-    } else {
-        "running".to_string()
-    }
+    !output.is_empty()
 }
 
 async fn chip_architecture() -> String {
@@ -71,66 +75,126 @@ async fn chip_architecture() -> String {
         .unwrap_or_default()
         .trim()
         .to_string();
-    
+
     // If we got a result, return it
     if !arch.is_empty() {
         return arch;
     }
-    
+
     // Fallback: try to read from /proc/cpuinfo
-    let cpuinfo = run_command("sh", &["-c", "cat /proc/cpuinfo | grep 'model name' | head -1 | cut -d: -f2 | tr -d ' '"])
-        .await
-        .unwrap_or_default()
-        .trim()
-        .to_string();
-    
+    let cpuinfo = run_command(
+        "sh",
+        &[
+            "-c",
+            "cat /proc/cpuinfo | grep 'model name' | head -1 | cut -d: -f2 | tr -d ' '",
+        ],
+    )
+    .await
+    .unwrap_or_default()
+    .trim()
+    .to_string();
+
     if !cpuinfo.is_empty() {
         return cpuinfo;
     }
-    
+
     // Final fallback
     "unknown".to_string()
 }
 
 async fn operating_system() -> String {
     // Try to get OS info from /etc/os-release
-    let os_info = run_command("sh", &["-c", "cat /etc/os-release | grep PRETTY_NAME | cut -d= -f2 | tr -d '\"'"])
-        .await
-        .unwrap_or_default()
-        .trim()
-        .to_string();
-    
+    let os_info = run_command(
+        "sh",
+        &[
+            "-c",
+            "cat /etc/os-release | grep PRETTY_NAME | cut -d= -f2 | tr -d '\"'",
+        ],
+    )
+    .await
+    .unwrap_or_default()
+    .trim()
+    .to_string();
+
     if !os_info.is_empty() {
         return os_info;
     }
-    
+
     // Fallback: try lsb_release
     let lsb_info = run_command("sh", &["-c", "lsb_release -d | cut -f2"])
         .await
         .unwrap_or_default()
         .trim()
         .to_string();
-    
+
     if !lsb_info.is_empty() {
         return lsb_info;
     }
-    
+
     // Final fallback: uname -a
     let uname_info = run_command("sh", &["-c", "uname -a"])
         .await
         .unwrap_or_default()
         .trim()
         .to_string();
-    
+
     if !uname_info.is_empty() {
         return uname_info;
     }
-    
+
     "unknown".to_string()
 }
 
-#[derive(Serialize)]
+/// Version of the `Metrics` payload below; bump it whenever a field changes meaning
+pub const METRICS_SCHEMA_VERSION: u32 = 2;
+
+/// Which payload shape to send to `/client_vitals`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsSchema {
+    /// Typed `Metrics`
+    #[default]
+    V2,
+    /// String-only `LegacyMetrics` for backends that predate v2
+    Legacy,
+}
+
+/// Device vitals. Units are in the field names and unavailable readings are `None`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Metrics {
+    pub schema_version: u32,
+    pub client_id: String,
+    pub timestamp: DateTime<Utc>,
+    pub daemon_version: String,
+    pub temp_celsius: Option<f32>,
+    pub cpu_percent: Option<f32>,
+    pub memory_percent: Option<f32>,
+    pub memory_used_bytes: Option<u64>,
+    pub memory_total_bytes: Option<u64>,
+    pub disk_percent: Option<f32>,
+    pub disk_used_bytes: Option<u64>,
+    pub disk_total_bytes: Option<u64>,
+    pub swap_percent: Option<f32>,
+    pub swap_used_bytes: Option<u64>,
+    pub swap_total_bytes: Option<u64>,
+    pub uptime_seconds: Option<u64>,
+    pub mpv_running: bool,
+    pub chip_architecture: String,
+    pub os: String,
+    /// True while playing from the on-disk schedule because the backend is unreachable
+    #[serde(default)]
+    pub schedule_cached: bool,
+    /// Time since the backend last confirmed the schedule; `None` before the first sync
+    #[serde(default)]
+    pub schedule_age_seconds: Option<u64>,
+    /// Transfers in flight when the sample was taken
+    #[serde(default)]
+    pub downloads: Vec<DownloadProgress>,
+}
+
+/// The original all-string payload
+#[derive(Serialize)]
+pub struct LegacyMetrics {
     client_id: String,
     temp: String,
     processes: String,
@@ -143,71 +207,165 @@ pub struct Metrics {
     os: String,
 }
 
-pub async fn collect_and_write_metrics(client_id: &str) -> Metrics {
+/// Formats an optional reading, leaving the field empty when it is unavailable
+fn reading(value: Option<f32>, precision: usize) -> String {
+    value
+        .map(|v| format!("{:.*}", precision, v))
+        .unwrap_or_default()
+}
+
+impl From<&Metrics> for LegacyMetrics {
+    fn from(metrics: &Metrics) -> Self {
+        LegacyMetrics {
+            client_id: metrics.client_id.clone(),
+            temp: reading(metrics.temp_celsius, 1),
+            processes: reading(metrics.cpu_percent, 1),
+            memory: reading(metrics.memory_percent, 1),
+            diskusage: reading(metrics.disk_percent, 0),
+            swapusage: reading(metrics.swap_percent, 1),
+            uptime: metrics
+                .uptime_seconds
+                .map(|s| s.to_string())
+                .unwrap_or_default(),
+            mpvstatus: if metrics.mpv_running {
+                "running"
+            } else {
+                "not running"
+            }
+            .to_string(),
+            chip_architecture: metrics.chip_architecture.clone(),
+            os: metrics.os.clone(),
+        }
+    }
+}
+
+/// Keeps the latest sample in `$HOME/.local/share/signage/metrics.json`
+fn write_latest(json: &str) -> Result<(), Box<dyn Error>> {
+    let mut file = File::create(format!("{}/metrics.json", data_dir()?))?;
+    file.write_all(json.as_bytes())?;
+    Ok(())
+}
+
+async fn collect_and_write_metrics(
+    client_id: &str,
+    collector: &mut SystemCollector,
+    player: &Player,
+) -> Metrics {
+    let stats = collector.collect();
+    let schedule = schedule_source();
     let metrics = Metrics {
+        schema_version: METRICS_SCHEMA_VERSION,
         client_id: client_id.to_string(),
-        temp: temp().await,
-        processes: cpu_usage().await,
-        memory: memory().await,
-        diskusage: disk_usage().await,
-        swapusage: swap_usage().await,
-        uptime: uptime().await,
-        mpvstatus: mpvstatus().await,
+        timestamp: Utc::now(),
+        daemon_version: VERSION.to_string(),
+        temp_celsius: stats.temp_celsius,
+        cpu_percent: stats.cpu_percent,
+        memory_percent: stats.memory.and_then(|u| u.percent()),
+        memory_used_bytes: stats.memory.map(|u| u.used_bytes),
+        memory_total_bytes: stats.memory.map(|u| u.total_bytes),
+        disk_percent: stats.disk.and_then(|u| u.percent()),
+        disk_used_bytes: stats.disk.map(|u| u.used_bytes),
+        disk_total_bytes: stats.disk.map(|u| u.total_bytes),
+        swap_percent: stats.swap.and_then(|u| u.percent()),
+        swap_used_bytes: stats.swap.map(|u| u.used_bytes),
+        swap_total_bytes: stats.swap.map(|u| u.total_bytes),
+        uptime_seconds: stats.uptime_secs,
+        mpv_running: player.is_running().await || mpv_process_running().await,
         chip_architecture: chip_architecture().await,
         os: operating_system().await,
+        schedule_cached: schedule.is_some_and(|source| source.cached),
+        schedule_age_seconds: schedule
+            .and_then(|source| (Utc::now() - source.synced_at).to_std().ok())
+            .map(|age| age.as_secs()),
+        downloads: download_progress(),
     };
 
-    // Serialize metrics to JSON
-    let json = serde_json::to_string_pretty(&metrics).expect("Failed to serialize metrics");
+    // Serialize metrics to JSON and write it to a file, without letting a full disk take the daemon down
+    match serde_json::to_string_pretty(&metrics) {
+        Ok(json) => {
+            if let Err(e) = write_latest(&json) {
+                eprintln!("Failed to write metrics.json: {}", e);
+            }
 
-    // Write JSON to a file
-    let mut file = File::create("metrics.json").expect("Failed to create file");
-    file.write_all(json.as_bytes())
-        .expect("Failed to write to file");
-
-    // Print to console for verification
-    println!("{}", json);
+            // Print to console for verification
+            println!("{}", json);
+        }
+        Err(e) => eprintln!("Failed to serialize metrics: {}", e),
+    }
 
     metrics
 }
 
-pub fn send_metrics(client_id: &str, metrics: &Metrics, api_key: &str, config: &Config) {
-    // Check if the client_id is a valid UUID
-    if let Err(_) = Uuid::parse_str(client_id) {
-        println!("Invalid client ID format: {}", client_id);
-        return;
+/// Samples the vitals every `SAMPLE_INTERVAL` and publishes each one as the latest, whether or
+/// not the backend is reachable
+pub async fn run_sampler(
+    client_id: String,
+    player: Player,
+    latest: watch::Sender<Option<Metrics>>,
+) {
+    let mut collector = SystemCollector::new("/");
+    let mut interval = time::interval(SAMPLE_INTERVAL);
+    // A slow sample shouldn't cause a burst of them afterwards
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let metrics = collect_and_write_metrics(&client_id, &mut collector, &player).await;
+        latest.send_replace(Some(metrics));
     }
+}
 
-    let client = Client::new();
-    let url = format!("{}/client_vitals/{}", config.url, client_id);
+/// Sends `metrics`, then uploads any backlog left over from earlier failures.
+///
+/// A sample that cannot be delivered is kept on disk and retried in a later batch. Legacy
+/// payloads carry no timestamp, so they are never buffered.
+pub async fn send_metrics(
+    api: &SignageApi,
+    metrics: &Metrics,
+    config: &MetricsConfig,
+) -> Result<(), Box<dyn Error>> {
+    // Check if the client_id is a valid UUID
+    if Uuid::parse_str(&metrics.client_id).is_err() {
+        return Err(format!("Invalid client ID format: {}", metrics.client_id).into());
+    }
 
-    // Print the URL for debugging
     println!("Sending metrics to daddy");
+    let sent = match config.schema {
+        MetricsSchema::V2 => api.send_metrics(metrics).await,
+        MetricsSchema::Legacy => {
+            return Ok(api.send_metrics(&LegacyMetrics::from(metrics)).await?)
+        }
+    };
+
+    let buffer = config.buffer()?;
+    if let Err(e) = sent {
+        buffer.push(metrics).await?;
+        println!("Buffered metrics sample ({} pending)", buffer.len().await?);
+        return Err(e.into());
+    }
+    println!("Successfully sent metrics");
+
+    flush_buffer(api, &buffer, config.batch_size).await
+}
+
+/// Uploads buffered samples oldest first until the buffer is empty or a batch fails
+async fn flush_buffer(
+    api: &SignageApi,
+    buffer: &DiskQueue<Metrics>,
+    batch_size: usize,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        let batch = buffer.peek(batch_size.max(1)).await?;
+        if batch.is_empty() {
+            return Ok(());
+        }
 
-    let mut headers = HeaderMap::new();
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-    headers.insert(
-        "Apikey",
-        HeaderValue::from_str(api_key).expect("Invalid API key"),
-    );
-
-    let res = client
-        .post(&url)
-        .headers(headers)
-        .json(metrics)
-        .send()
-        .expect("Failed to send metrics");
-
-    let status = res.status();
-    if status.is_success() {
-        println!("Successfully sent metrics");
-    } else {
-        let error_text = res
-            .text()
-            .unwrap_or_else(|_| "Failed to read error text".to_string());
-        println!(
-            "Failed to send metrics: {:?}\nError: {}",
-            status, error_text
-        );
+        if !batch.records.is_empty() {
+            api.send_metrics_batch(&batch.records).await?;
+            println!(
+                "Uploaded {} buffered metrics sample(s)",
+                batch.records.len()
+            );
+        }
+        buffer.remove(&batch).await?;
     }
 }
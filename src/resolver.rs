@@ -0,0 +1,427 @@
+use crate::api::ClientPlaylistSchedule;
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use std::cmp::Ordering;
+use uuid::Uuid;
+
+/// How far ahead recurring schedules are expanded when looking for the next transition
+const HORIZON_DAYS: i64 = 366;
+/// Recurring schedules are expanded this many days at a time
+const CHUNK_DAYS: i64 = 7;
+
+/// One concrete window of a schedule
+type Window = (DateTime<Utc>, DateTime<Utc>);
+
+/// Picks the playlist to show at a given instant from a set of possibly overlapping schedules.
+///
+/// Windows are half-open, `[start, end)`, so back-to-back schedules never overlap at the
+/// boundary. Recurring schedules are expanded lazily in `tz`, and only the occurrences that fall
+/// inside their `start_time`..`end_time` range count. When several windows are active the
+/// winner is decided by, in order:
+///
+/// 1. the highest `priority`
+/// 2. the most recently updated schedule (schedules without `updated_at` lose)
+/// 3. the narrowest window
+/// 4. the lowest schedule id, so the outcome never depends on server order
+///
+/// Nothing here reads the clock; callers pass the instant to evaluate.
+pub struct ScheduleResolver<'a> {
+    schedules: &'a [ClientPlaylistSchedule],
+    tz: Tz,
+}
+
+impl<'a> ScheduleResolver<'a> {
+    pub fn new(schedules: &'a [ClientPlaylistSchedule], tz: Tz) -> Self {
+        ScheduleResolver { schedules, tz }
+    }
+
+    /// Windows of `schedule` that overlap `from..=to`
+    fn windows(
+        &self,
+        schedule: &ClientPlaylistSchedule,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Vec<Window> {
+        let Some(recurrence) = &schedule.recurrence else {
+            return vec![(schedule.start_time, schedule.end_time)];
+        };
+
+        let anchor = schedule.start_time.with_timezone(&self.tz).date_naive();
+        // An occurrence can run past midnight, so start a day early
+        let first = from.with_timezone(&self.tz).date_naive().pred_opt();
+        let last = to.with_timezone(&self.tz).date_naive();
+
+        first
+            .into_iter()
+            .flat_map(|first| first.iter_days().take_while(move |date| *date <= last))
+            .filter_map(|date| recurrence.occurrence(&self.tz, anchor, date))
+            .map(|(start, end)| (start.max(schedule.start_time), end.min(schedule.end_time)))
+            .filter(|(start, end)| start < end && *start <= to && *end >= from)
+            .collect()
+    }
+
+    /// `Ordering::Greater` when `a` wins over `b`
+    fn precedence(
+        (a, a_window): &(&ClientPlaylistSchedule, Window),
+        (b, b_window): &(&ClientPlaylistSchedule, Window),
+    ) -> Ordering {
+        a.priority
+            .cmp(&b.priority)
+            .then_with(|| a.updated_at.cmp(&b.updated_at))
+            .then_with(|| (b_window.1 - b_window.0).cmp(&(a_window.1 - a_window.0)))
+            .then_with(|| b.id.cmp(&a.id))
+    }
+
+    /// The winning schedule at `at`, if any window is active
+    pub fn resolve(&self, at: DateTime<Utc>) -> Option<&'a ClientPlaylistSchedule> {
+        self.schedules
+            .iter()
+            .flat_map(|schedule| {
+                self.windows(schedule, at, at)
+                    .into_iter()
+                    .filter(|(start, end)| *start <= at && at < *end)
+                    .map(move |window| (schedule, window))
+            })
+            .max_by(Self::precedence)
+            .map(|(schedule, _)| schedule)
+    }
+
+    /// The playlist that should be showing at `at`
+    pub fn playlist_at(&self, at: DateTime<Utc>) -> Option<Uuid> {
+        self.resolve(at).map(|schedule| schedule.playlist_id)
+    }
+
+    /// The first instant after `after` at which the winning playlist changes, if there is one
+    /// within the next year
+    pub fn next_transition(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let current = self.playlist_at(after);
+        let recurring = self
+            .schedules
+            .iter()
+            .any(|schedule| schedule.recurrence.is_some());
+        let horizon = if recurring {
+            after + Duration::days(HORIZON_DAYS)
+        } else {
+            self.schedules
+                .iter()
+                .map(|schedule| schedule.end_time)
+                .max()?
+        };
+
+        let mut from = after;
+        while from < horizon {
+            let to = if recurring {
+                (from + Duration::days(CHUNK_DAYS)).min(horizon)
+            } else {
+                horizon
+            };
+
+            let mut boundaries: Vec<DateTime<Utc>> = self
+                .schedules
+                .iter()
+                .flat_map(|schedule| self.windows(schedule, from, to))
+                .flat_map(|(start, end)| [start, end])
+                .filter(|boundary| *boundary > from && *boundary <= to)
+                .collect();
+            boundaries.sort();
+            boundaries.dedup();
+
+            if let Some(boundary) = boundaries
+                .into_iter()
+                .find(|boundary| self.playlist_at(*boundary) != current)
+            {
+                return Some(boundary);
+            }
+            from = to;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recurrence::Recurrence;
+
+    fn at(instant: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(instant)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn schedule(id: u128, start: &str, end: &str) -> ClientPlaylistSchedule {
+        ClientPlaylistSchedule {
+            id: Uuid::from_u128(id),
+            // Each schedule plays its own playlist, so the winner is visible from `playlist_at`
+            playlist_id: Uuid::from_u128(id),
+            device_id: Uuid::nil(),
+            organization_id: Uuid::nil(),
+            start_time: at(start),
+            end_time: at(end),
+            playlist_name: format!("playlist {}", id),
+            priority: 0,
+            recurrence: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    fn recurring(id: u128, start: &str, end: &str, rule: &str) -> ClientPlaylistSchedule {
+        ClientPlaylistSchedule {
+            recurrence: Some(serde_json::from_str::<Recurrence>(rule).unwrap()),
+            ..schedule(id, start, end)
+        }
+    }
+
+    fn winner(schedules: &[ClientPlaylistSchedule], now: &str) -> Option<u128> {
+        ScheduleResolver::new(schedules, Tz::UTC)
+            .resolve(at(now))
+            .map(|schedule| schedule.id.as_u128())
+    }
+
+    #[test]
+    fn windows_are_half_open() {
+        let schedules = [
+            schedule(1, "2026-01-05T10:00:00Z", "2026-01-05T12:00:00Z"),
+            schedule(2, "2026-01-05T12:00:00Z", "2026-01-05T14:00:00Z"),
+        ];
+        let cases = [
+            ("2026-01-05T09:59:59Z", None),
+            ("2026-01-05T10:00:00Z", Some(1)),
+            ("2026-01-05T11:59:59Z", Some(1)),
+            ("2026-01-05T12:00:00Z", Some(2)),
+            ("2026-01-05T13:59:59Z", Some(2)),
+            ("2026-01-05T14:00:00Z", None),
+        ];
+        for (now, expected) in cases {
+            assert_eq!(winner(&schedules, now), expected, "at {}", now);
+        }
+    }
+
+    #[test]
+    fn precedence_order() {
+        let now = "2026-01-05T11:00:00Z";
+        let wide = |id| schedule(id, "2026-01-05T08:00:00Z", "2026-01-05T18:00:00Z");
+        let narrow = |id| schedule(id, "2026-01-05T10:00:00Z", "2026-01-05T12:00:00Z");
+        let updated = |mut schedule: ClientPlaylistSchedule, instant| {
+            schedule.updated_at = Some(at(instant));
+            schedule
+        };
+        let priority = |mut schedule: ClientPlaylistSchedule, priority| {
+            schedule.priority = priority;
+            schedule
+        };
+
+        let cases = [
+            (
+                "higher priority beats recency and width",
+                priority(wide(1), 1),
+                updated(narrow(2), "2026-01-05T00:00:00Z"),
+                1,
+            ),
+            (
+                "more recently updated beats width",
+                updated(wide(1), "2026-01-04T00:00:00Z"),
+                updated(narrow(2), "2026-01-03T00:00:00Z"),
+                1,
+            ),
+            (
+                "never updated loses to updated",
+                wide(1),
+                updated(wide(2), "2026-01-01T00:00:00Z"),
+                2,
+            ),
+            ("narrower window wins a tie", wide(1), narrow(2), 2),
+            ("lower id breaks a full tie", wide(2), wide(1), 1),
+        ];
+        for (name, a, b, expected) in cases {
+            let forward = [a.clone(), b.clone()];
+            let backward = [b, a];
+            assert_eq!(winner(&forward, now), Some(expected), "{}", name);
+            assert_eq!(
+                winner(&backward, now),
+                Some(expected),
+                "{} (reversed)",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn overlapping_schedules_and_their_transitions() {
+        let mut schedules = [
+            schedule(1, "2026-01-05T08:00:00Z", "2026-01-05T20:00:00Z"),
+            schedule(2, "2026-01-05T10:00:00Z", "2026-01-05T12:00:00Z"),
+            schedule(3, "2026-01-05T11:00:00Z", "2026-01-05T13:00:00Z"),
+        ];
+        schedules[1].priority = 1;
+        schedules[2].priority = 2;
+        let resolver = ScheduleResolver::new(&schedules, Tz::UTC);
+
+        // (now, winner, next transition)
+        let cases = [
+            ("2026-01-05T07:00:00Z", None, Some("2026-01-05T08:00:00Z")),
+            (
+                "2026-01-05T09:00:00Z",
+                Some(1),
+                Some("2026-01-05T10:00:00Z"),
+            ),
+            (
+                "2026-01-05T10:30:00Z",
+                Some(2),
+                Some("2026-01-05T11:00:00Z"),
+            ),
+            // 2's end at 12:00 changes nothing while 3 is on top
+            (
+                "2026-01-05T11:30:00Z",
+                Some(3),
+                Some("2026-01-05T13:00:00Z"),
+            ),
+            (
+                "2026-01-05T13:00:00Z",
+                Some(1),
+                Some("2026-01-05T20:00:00Z"),
+            ),
+            ("2026-01-05T20:00:00Z", None, None),
+        ];
+        for (now, expected, next) in cases {
+            assert_eq!(
+                resolver.playlist_at(at(now)),
+                expected.map(Uuid::from_u128),
+                "at {}",
+                now
+            );
+            assert_eq!(
+                resolver.next_transition(at(now)),
+                next.map(at),
+                "after {}",
+                now
+            );
+        }
+    }
+
+    #[test]
+    fn nothing_matches_without_schedules_or_outside_their_windows() {
+        assert_eq!(winner(&[], "2026-01-05T11:00:00Z"), None);
+        assert_eq!(
+            ScheduleResolver::new(&[], Tz::UTC).next_transition(at("2026-01-05T11:00:00Z")),
+            None
+        );
+
+        let schedules = [recurring(
+            1,
+            "2026-01-01T00:00:00Z",
+            "2026-02-01T00:00:00Z",
+            r#"{"days": "weekdays", "start": "09:00", "end": "17:00"}"#,
+        )];
+        // A Saturday, a weekday evening, and a weekday after the range ended
+        assert_eq!(winner(&schedules, "2026-01-10T12:00:00Z"), None);
+        assert_eq!(winner(&schedules, "2026-01-05T18:00:00Z"), None);
+        assert_eq!(winner(&schedules, "2026-02-02T12:00:00Z"), None);
+        assert_eq!(winner(&schedules, "2026-01-05T12:00:00Z"), Some(1));
+    }
+
+    #[test]
+    fn next_transition_across_midnight() {
+        let schedules = [recurring(
+            1,
+            "2026-01-01T00:00:00Z",
+            "2026-02-01T00:00:00Z",
+            r#"{"days": "daily", "start": "22:00", "end": "02:00"}"#,
+        )];
+        let resolver = ScheduleResolver::new(&schedules, Tz::UTC);
+
+        assert_eq!(winner(&schedules, "2026-01-05T23:00:00Z"), Some(1));
+        assert_eq!(winner(&schedules, "2026-01-06T01:59:59Z"), Some(1));
+        assert_eq!(winner(&schedules, "2026-01-06T02:00:00Z"), None);
+        assert_eq!(
+            resolver.next_transition(at("2026-01-05T23:00:00Z")),
+            Some(at("2026-01-06T02:00:00Z"))
+        );
+        assert_eq!(
+            resolver.next_transition(at("2026-01-06T03:00:00Z")),
+            Some(at("2026-01-06T22:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn next_transition_across_dst_changes() {
+        let london: Tz = "Europe/London".parse().unwrap();
+        let spring = [recurring(
+            1,
+            "2026-03-01T00:00:00Z",
+            "2026-04-01T00:00:00Z",
+            r#"{"days": "daily", "start": "01:30", "end": "03:00"}"#,
+        )];
+        let resolver = ScheduleResolver::new(&spring, london);
+
+        // The day before, still on GMT
+        assert_eq!(
+            resolver.next_transition(at("2026-03-28T00:00:00Z")),
+            Some(at("2026-03-28T01:30:00Z"))
+        );
+        // 01:30 is skipped on the 29th, so the window opens as the clocks jump to 02:00 BST
+        assert_eq!(
+            resolver.next_transition(at("2026-03-29T00:00:00Z")),
+            Some(at("2026-03-29T01:00:00Z"))
+        );
+        assert_eq!(
+            resolver.next_transition(at("2026-03-29T01:00:00Z")),
+            Some(at("2026-03-29T02:00:00Z"))
+        );
+
+        let autumn = [recurring(
+            1,
+            "2026-10-01T00:00:00Z",
+            "2026-11-01T00:00:00Z",
+            r#"{"days": "daily", "start": "01:30", "end": "04:00"}"#,
+        )];
+        let resolver = ScheduleResolver::new(&autumn, london);
+
+        // 01:30 happens twice on the 25th; the window opens at the first, still in BST
+        assert_eq!(
+            resolver.next_transition(at("2026-10-25T00:00:00Z")),
+            Some(at("2026-10-25T00:30:00Z"))
+        );
+        assert_eq!(
+            resolver.next_transition(at("2026-10-25T00:30:00Z")),
+            Some(at("2026-10-25T04:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn recurring_transitions_are_only_searched_for_a_year() {
+        let after = at("2026-01-01T00:00:00Z");
+        let starting_in = |days: i64| {
+            let start = after + Duration::days(days);
+            ClientPlaylistSchedule {
+                start_time: start,
+                end_time: start + Duration::days(30),
+                ..recurring(
+                    1,
+                    "2026-01-01T00:00:00Z",
+                    "2026-01-02T00:00:00Z",
+                    r#"{"days": "daily", "start": "09:00", "end": "17:00"}"#,
+                )
+            }
+        };
+
+        let near = [starting_in(300)];
+        assert_eq!(
+            ScheduleResolver::new(&near, Tz::UTC).next_transition(after),
+            Some(after + Duration::days(300) + Duration::hours(9))
+        );
+        let far = [starting_in(HORIZON_DAYS + 10)];
+        assert_eq!(
+            ScheduleResolver::new(&far, Tz::UTC).next_transition(after),
+            None
+        );
+
+        // One-off windows have no horizon
+        let one_off = [schedule(1, "2028-01-01T00:00:00Z", "2028-01-02T00:00:00Z")];
+        assert_eq!(
+            ScheduleResolver::new(&one_off, Tz::UTC).next_transition(after),
+            Some(at("2028-01-01T00:00:00Z"))
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::time::Duration;
+
+/// Errors that know whether the failed operation is worth repeating
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+
+    /// Delay requested by the server, e.g. via a `Retry-After` header
+    fn retry_after(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Exponential backoff with full jitter, configured under `retry` in signage.json
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Total attempts including the first one
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay_ms: 500,
+            max_delay_ms: 30_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Random delay in `[0, min(max_delay, base_delay * 2^attempt)]`
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay_ms
+            .saturating_mul(1u64 << attempt.min(32))
+            .min(self.max_delay_ms);
+        Duration::from_millis(rand::thread_rng().gen_range(0..=ceiling))
+    }
+
+    /// Delay before retry number `attempt`: what the server asked for if that is longer than
+    /// the backoff, but never more than `max_delay_ms`
+    pub fn delay<E: Retryable>(&self, attempt: u32, error: &E) -> Duration {
+        let backoff = self.backoff(attempt);
+        match error.retry_after() {
+            Some(retry_after) => retry_after
+                .min(Duration::from_millis(self.max_delay_ms))
+                .max(backoff),
+            None => backoff,
+        }
+    }
+
+    /// Runs `op` until it succeeds, fails with a non-retryable error or runs out of attempts
+    pub async fn run<T, E, F, Fut>(&self, mut op: F) -> Result<T, E>
+    where
+        E: Retryable + std::fmt::Display,
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match op().await {
+                Ok(value) => return Ok(value),
+                Err(e) if e.is_retryable() && attempt + 1 < self.max_attempts => {
+                    let delay = self.delay(attempt, &e);
+                    println!("Request failed ({}), retrying in {:?}", e, delay);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Parses a `Retry-After` header given either as seconds or as an HTTP date
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
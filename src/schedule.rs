@@ -0,0 +1,500 @@
+use crate::api::{ApiError, ClientPlaylistSchedule, SignageApi};
+use crate::config::Config;
+use crate::content;
+use crate::data::Data;
+use crate::display;
+use crate::recurrence::device_timezone;
+use crate::resolver::ScheduleResolver;
+use crate::telemetry::{count, schedule_source, set_schedule_source, ScheduleSource, COUNTERS};
+use crate::util::{data_dir, ClientTimelineScheduleResponse, ClientUpdateFlagsResponse};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::{boxed::Box, error::Error};
+use tokio::fs;
+use tokio::sync::watch;
+use tokio::time::{self, Duration};
+use uuid::Uuid;
+
+/// Upper bound on one scheduler sleep, so a wall clock that jumps (NTP sync after boot, manual
+/// changes) can't leave a transition waiting on a stale timer
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// Settings under `schedule` in signage.json
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ScheduleConfig {
+    /// How long a schedule keeps playing without reaching the backend; 0 keeps it forever
+    pub cache_max_age_hours: u64,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        ScheduleConfig {
+            cache_max_age_hours: 7 * 24,
+        }
+    }
+}
+
+impl ScheduleConfig {
+    fn is_expired(&self, synced_at: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        self.cache_max_age_hours > 0
+            && now - synced_at > chrono::Duration::hours(self.cache_max_age_hours as i64)
+    }
+}
+
+/// The last schedule list fetched from the backend, as stored in schedule_cache.json
+#[derive(Serialize, Deserialize)]
+struct CachedSchedule {
+    fetched_at: DateTime<Utc>,
+    schedules: Vec<ClientPlaylistSchedule>,
+}
+
+fn cache_path() -> Result<String, Box<dyn Error>> {
+    Ok(format!("{}/schedule_cache.json", data_dir()?))
+}
+
+/// Writes `schedules` to the cache through a temporary file, so a power cut never leaves half
+/// a schedule behind
+async fn save_cache(schedules: &[ClientPlaylistSchedule]) -> Result<(), Box<dyn Error>> {
+    let path = cache_path()?;
+    let tmp = format!("{}.tmp", path);
+    let cached = CachedSchedule {
+        fetched_at: Utc::now(),
+        schedules: schedules.to_vec(),
+    };
+    fs::write(&tmp, serde_json::to_vec(&cached)?).await?;
+    fs::rename(&tmp, &path).await?;
+    Ok(())
+}
+
+/// The cached schedule list, or no schedules at all when it has expired, so the fallback
+/// playlist takes over even after rebooting offline. `None` when nothing usable is cached.
+pub async fn load_cache(config: &ScheduleConfig) -> Option<Vec<ClientPlaylistSchedule>> {
+    read_cache(&cache_path().ok()?, config, Utc::now()).await
+}
+
+async fn read_cache(
+    path: &str,
+    config: &ScheduleConfig,
+    now: DateTime<Utc>,
+) -> Option<Vec<ClientPlaylistSchedule>> {
+    let contents = fs::read(path).await.ok()?;
+    let cached: CachedSchedule = match serde_json::from_slice(&contents) {
+        Ok(cached) => cached,
+        Err(e) => {
+            eprintln!("Ignoring unreadable schedule cache: {}", e);
+            return None;
+        }
+    };
+
+    set_schedule_source(Some(ScheduleSource {
+        synced_at: cached.fetched_at,
+        cached: true,
+    }));
+    if config.is_expired(cached.fetched_at, now) {
+        println!("Cached schedule from {} has expired", cached.fetched_at);
+        return Some(Vec::new());
+    }
+
+    println!(
+        "Loaded {} cached schedule(s) from {}",
+        cached.schedules.len(),
+        cached.fetched_at
+    );
+    Some(cached.schedules)
+}
+
+/// The playlist to show and whether it is the fallback: the winning schedule's playlist, else
+/// the timeline's fallback, else `default_playlist`
+fn target(
+    active: Option<&ClientPlaylistSchedule>,
+    fallback_playlist: Option<Uuid>,
+    default_playlist: Option<Uuid>,
+) -> Option<(Uuid, bool)> {
+    match active {
+        Some(schedule) => Some((schedule.playlist_id, false)),
+        None => fallback_playlist
+            .or(default_playlist)
+            .map(|playlist| (playlist, true)),
+    }
+}
+
+/// Switches to the playlist of the winning schedule window, or to the fallback playlist
+/// (from the timeline, else `config.default_playlist_id`) when no window is active.
+///
+/// This is the only place the current playlist changes. The choice is made and recorded in
+/// one turn on data.json; the backend is told afterwards.
+pub async fn process_schedules(
+    api: &SignageApi,
+    config: &Config,
+    schedules: &[ClientPlaylistSchedule],
+    tz: Tz,
+    now: DateTime<Utc>,
+) -> Result<(), Box<dyn Error>> {
+    let active = ScheduleResolver::new(schedules, tz).resolve(now);
+    let schedule_id = active.map(|schedule| schedule.id);
+
+    let switched = Data::update(|data| {
+        // With nothing scheduled and no fallback configured, keep playing what we have
+        let (target, fallback) = target(
+            active,
+            data.fallback_playlist_id,
+            config.default_playlist_id,
+        )?;
+        data.current_schedule = schedule_id;
+        data.playing_fallback = Some(fallback);
+        if data.current_playlist == Some(target) {
+            return None;
+        }
+        data.current_playlist = Some(target);
+        Some((target, fallback))
+    })
+    .await
+    .map_err(|e| format!("Failed to update data.json: {}", e))?;
+
+    let Some((target, fallback)) = switched else {
+        return Ok(());
+    };
+    if fallback {
+        println!(
+            "No active schedule - switching to fallback playlist {}",
+            target
+        );
+    } else {
+        println!("Schedule updated - restarting OMNIPLAYER");
+    }
+    count(&COUNTERS.schedule_switches);
+    content::request_sync();
+
+    // Update playlist ID in backend
+    if let Err(e) = api.update_playlist_id(target, fallback).await {
+        eprintln!("Error updating playlist ID: {}", e);
+    }
+    Ok(())
+}
+
+/// Parses the string ids the timeline endpoint uses, ignoring empty or malformed ones
+fn playlist_id(id: &Option<String>) -> Option<Uuid> {
+    id.as_deref().and_then(|id| Uuid::parse_str(id).ok())
+}
+
+/// Stores the timeline in data.json and acts on its update flags, which are returned so the
+/// caller can refresh the schedule when asked to.
+///
+/// The timeline's active playlist is not switched to here: a playlist update refreshes the
+/// schedule list instead, and the scheduler picks the playlist from it.
+pub async fn apply_timeline(
+    timeline: &ClientTimelineScheduleResponse,
+) -> Result<ClientUpdateFlagsResponse, Box<dyn Error>> {
+    let flags = timeline.update_flags.clone().unwrap_or_default();
+    if flags.playlist_update_needed {
+        println!("Playlist update requested");
+    }
+    if flags.content_update_needed {
+        println!("Content update requested");
+    }
+
+    // A change flag names the new values; otherwise the timeline carries the current ones
+    let (layout, rotation) = if flags.layout_change {
+        println!(
+            "Layout change requested: {:?}, rotation {:?}",
+            flags.current_layout, flags.current_rotation
+        );
+        (
+            flags.current_layout.clone().or(timeline.layout.clone()),
+            flags.current_rotation.or(timeline.rotation),
+        )
+    } else {
+        (timeline.layout.clone(), timeline.rotation)
+    };
+
+    // An unsupported layout never becomes the applied one, so a new request is what counts
+    let relayout = Data::update(|data| {
+        data.active_schedule_ends = timeline.schedule_ends_at.clone();
+        data.next_schedule_starts = timeline.next_schedule_starts_at.clone();
+        data.next_playlist_id = playlist_id(&timeline.next_playlist_id);
+        data.fallback_playlist_id = playlist_id(&timeline.fallback_playlist_id);
+        let new_layout = layout.is_some() && layout != data.layout;
+        if layout.is_some() {
+            data.layout = layout;
+        }
+        if rotation.is_some() {
+            data.rotation = rotation;
+        }
+        new_layout || data.rotation != data.applied_rotation
+    })
+    .await?;
+
+    if flags.content_update_needed {
+        content::request_sync();
+    }
+    if relayout {
+        display::request_apply();
+    }
+    Ok(flags)
+}
+
+/// Polls the timeline and publishes the raw schedule list to `schedules`, where the scheduler
+/// task picks it up.
+///
+/// The list is only fetched when the timeline says it or the playlist changed, when nothing has been fetched
+/// yet, after running from the cache, from backends that predate the timeline endpoint, or when
+/// `force` is set.
+/// Otherwise the current list is re-published as is, so a fallback playlist changed by the
+/// timeline is still picked up.
+pub async fn update(
+    api: &SignageApi,
+    config: &ScheduleConfig,
+    schedules: &watch::Sender<Option<Vec<ClientPlaylistSchedule>>>,
+    force: bool,
+) -> Result<(), Box<dyn Error>> {
+    println!("Updating Schedule");
+    let refetch = match api.timeline().await {
+        Ok(timeline) => {
+            let flags = apply_timeline(&timeline).await?;
+            flags.schedule_update_needed || flags.playlist_update_needed
+        }
+        Err(ApiError::NotFound(_)) => true,
+        Err(e) => {
+            run_offline(config, schedules);
+            return Err(format!("Failed to retrieve timeline: {}", e).into());
+        }
+    };
+
+    // Change flags may have been missed while we were offline
+    let from_cache = match schedule_source() {
+        Some(source) => source.cached,
+        None => true,
+    };
+    if force || refetch || from_cache || schedules.borrow().is_none() {
+        let list = match api.playlist_schedule().await {
+            Ok(list) => list,
+            Err(e) => {
+                run_offline(config, schedules);
+                return Err(e.into());
+            }
+        };
+        for schedule in &list {
+            if let Some(Err(e)) = schedule
+                .recurrence
+                .as_ref()
+                .map(|recurrence| recurrence.validate())
+            {
+                eprintln!(
+                    "Schedule {} has an invalid recurrence and will not play: {}",
+                    schedule.id, e
+                );
+            }
+        }
+        if let Err(e) = save_cache(&list).await {
+            eprintln!("Failed to cache schedule: {}", e);
+        }
+        schedules.send_replace(Some(list));
+    } else {
+        schedules.send_modify(|_| ());
+    }
+
+    set_schedule_source(Some(ScheduleSource {
+        synced_at: Utc::now(),
+        cached: false,
+    }));
+    Ok(())
+}
+
+/// Keeps playing the schedule we already have while the backend is unreachable, and drops it
+/// once it is older than `max_age_hours` so the fallback playlist takes over
+fn run_offline(
+    config: &ScheduleConfig,
+    schedules: &watch::Sender<Option<Vec<ClientPlaylistSchedule>>>,
+) {
+    let Some(source) = schedule_source() else {
+        return;
+    };
+
+    if config.is_expired(source.synced_at, Utc::now()) {
+        if schedules
+            .borrow()
+            .as_ref()
+            .is_some_and(|list| !list.is_empty())
+        {
+            println!("Schedule last synced {} has expired", source.synced_at);
+            schedules.send_replace(Some(Vec::new()));
+        }
+    } else if !source.cached {
+        println!(
+            "Backend unreachable, running from the schedule synced {}",
+            source.synced_at
+        );
+    }
+    set_schedule_source(Some(ScheduleSource {
+        cached: true,
+        ..source
+    }));
+}
+
+/// Switches playlists at the exact schedule boundaries, independently of the polling loop.
+///
+/// Sleeps until the resolver's next transition and re-evaluates there, or as soon as a new
+/// schedule list is published on `schedules`. Once the next transition is within the preload
+/// lead time its playlist is published on `upcoming`.
+pub async fn run(
+    client: Client,
+    config: watch::Receiver<Config>,
+    mut schedules: watch::Receiver<Option<Vec<ClientPlaylistSchedule>>>,
+    upcoming: watch::Sender<Option<Uuid>>,
+) {
+    let tz = device_timezone(config.borrow().timezone.as_deref());
+    println!("Evaluating recurring schedules in {}", tz);
+    loop {
+        // The latest config, so a rotated API key is picked up
+        let config = config.borrow().clone();
+        let api = SignageApi::new(client.clone(), &config);
+        let lead = chrono::Duration::minutes(config.content.preload_lead_minutes as i64);
+        let current = schedules.borrow_and_update().clone();
+        let now = Utc::now();
+
+        let (next, next_playlist) = match &current {
+            Some(current) => {
+                if let Err(e) = process_schedules(&api, &config, current, tz, now).await {
+                    eprintln!("Error processing schedule: {}", e);
+                }
+                let resolver = ScheduleResolver::new(current, tz);
+                let next = resolver.next_transition(now);
+                let next_playlist = next
+                    .filter(|next| *next - now <= lead)
+                    .and_then(|next| resolver.playlist_at(next));
+                (next, next_playlist)
+            }
+            None => (None, None),
+        };
+
+        upcoming.send_if_modified(|upcoming| {
+            let changed = *upcoming != next_playlist;
+            *upcoming = next_playlist;
+            changed
+        });
+
+        // A boundary that passed while we were processing is due now
+        let sleep = next
+            .map(|next| (next - Utc::now()).to_std().unwrap_or_default())
+            .map_or(MAX_SLEEP, |until| until.min(MAX_SLEEP));
+
+        tokio::select! {
+            _ = time::sleep(sleep) => (),
+            changed = schedules.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(playlist_id: Uuid) -> ClientPlaylistSchedule {
+        ClientPlaylistSchedule {
+            id: Uuid::new_v4(),
+            playlist_id,
+            device_id: Uuid::nil(),
+            organization_id: Uuid::nil(),
+            // Playing now
+            start_time: Utc::now() - chrono::Duration::hours(1),
+            end_time: Utc::now() + chrono::Duration::hours(1),
+            playlist_name: String::new(),
+            priority: 0,
+            recurrence: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    /// Writes a cache fetched `age_hours` before `now` holding one schedule for `playlist`
+    async fn cache(
+        dir: &tempfile::TempDir,
+        now: DateTime<Utc>,
+        age_hours: i64,
+        playlist: Uuid,
+    ) -> String {
+        let path = dir
+            .path()
+            .join("schedule_cache.json")
+            .to_str()
+            .unwrap()
+            .to_string();
+        let cached = CachedSchedule {
+            fetched_at: now - chrono::Duration::hours(age_hours),
+            schedules: vec![schedule(playlist)],
+        };
+        fs::write(&path, serde_json::to_vec(&cached).unwrap())
+            .await
+            .unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn rebooting_offline_with_an_expired_cache_plays_the_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ScheduleConfig {
+            cache_max_age_hours: 24,
+        };
+        let (scheduled, fallback) = (Uuid::new_v4(), Uuid::new_v4());
+        let now = Utc::now();
+
+        // Still fresh: the cached schedule keeps playing
+        let path = cache(&dir, now, 23, scheduled).await;
+        let list = read_cache(&path, &config, now).await.unwrap();
+        let active = ScheduleResolver::new(&list, Tz::UTC).resolve(now);
+        assert_eq!(
+            target(active, Some(fallback), None),
+            Some((scheduled, false))
+        );
+
+        // Expired: no schedules rather than none loaded, so the scheduler still switches
+        let path = cache(&dir, now, 25, scheduled).await;
+        let list = read_cache(&path, &config, now).await.unwrap();
+        assert!(list.is_empty());
+        let active = ScheduleResolver::new(&list, Tz::UTC).resolve(now);
+        assert_eq!(target(active, Some(fallback), None), Some((fallback, true)));
+        assert!(schedule_source().is_some_and(|source| source.cached));
+    }
+
+    #[tokio::test]
+    async fn an_unreadable_cache_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("schedule_cache.json");
+        fs::write(&path, "{").await.unwrap();
+        let config = ScheduleConfig::default();
+        assert!(read_cache(path.to_str().unwrap(), &config, Utc::now())
+            .await
+            .is_none());
+        assert!(read_cache("/nonexistent/cache.json", &config, Utc::now())
+            .await
+            .is_none());
+    }
+
+    #[test]
+    fn target_prefers_the_schedule_then_the_timeline_then_the_config() {
+        let (scheduled, timeline, default) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let active = schedule(scheduled);
+
+        let cases = [
+            (
+                Some(&active),
+                Some(timeline),
+                Some(default),
+                Some((scheduled, false)),
+            ),
+            (None, Some(timeline), Some(default), Some((timeline, true))),
+            (None, None, Some(default), Some((default, true))),
+            (None, None, None, None),
+        ];
+        for (active, fallback, default, expected) in cases {
+            assert_eq!(target(active, fallback, default), expected);
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Bytes used out of bytes available for memory, swap or disk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub used_bytes: u64,
+    pub total_bytes: u64,
+}
+
+impl Usage {
+    /// Percentage used, or `None` when there is nothing to use (e.g. no swap configured)
+    pub fn percent(&self) -> Option<f32> {
+        if self.total_bytes == 0 {
+            return None;
+        }
+        Some((self.used_bytes as f64 / self.total_bytes as f64 * 100.0) as f32)
+    }
+}
+
+/// One snapshot of the device vitals. Readings that could not be taken are `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SystemStats {
+    pub temp_celsius: Option<f32>,
+    pub cpu_percent: Option<f32>,
+    pub memory: Option<Usage>,
+    pub swap: Option<Usage>,
+    pub disk: Option<Usage>,
+    pub uptime_secs: Option<u64>,
+}
+
+/// Aggregate jiffies from the `cpu` line of /proc/stat
+#[derive(Debug, Clone, Copy)]
+struct CpuTimes {
+    idle: u64,
+    total: u64,
+}
+
+/// Reads vitals straight from procfs, sysfs and `statvfs`.
+///
+/// All paths are resolved below `root`, so pointing it at a directory laid out like
+/// `/proc` and `/sys` gives deterministic readings.
+pub struct SystemCollector {
+    root: PathBuf,
+    last_cpu: Option<CpuTimes>,
+}
+
+impl SystemCollector {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        let mut collector = SystemCollector {
+            root: root.into(),
+            last_cpu: None,
+        };
+        // Prime the CPU counters so the first sample already has a delta to work with
+        collector.last_cpu = collector.cpu_times();
+        collector
+    }
+
+    pub fn collect(&mut self) -> SystemStats {
+        let meminfo = self.read("proc/meminfo").map(|s| parse_meminfo(&s));
+
+        SystemStats {
+            temp_celsius: self.temp_celsius(),
+            cpu_percent: self.cpu_percent(),
+            memory: meminfo.as_ref().and_then(|m| m.memory()),
+            swap: meminfo.as_ref().and_then(|m| m.swap()),
+            disk: disk_usage(&self.root),
+            uptime_secs: self.read("proc/uptime").and_then(|s| parse_uptime(&s)),
+        }
+    }
+
+    fn read(&self, path: &str) -> Option<String> {
+        fs::read_to_string(self.root.join(path)).ok()
+    }
+
+    fn cpu_times(&self) -> Option<CpuTimes> {
+        self.read("proc/stat").and_then(|s| parse_cpu_times(&s))
+    }
+
+    /// CPU busy percentage since the previous call
+    fn cpu_percent(&mut self) -> Option<f32> {
+        let current = self.cpu_times()?;
+        let previous = self.last_cpu.replace(current)?;
+
+        let total = current.total.checked_sub(previous.total)?;
+        let idle = current.idle.checked_sub(previous.idle)?;
+        if total == 0 {
+            return None;
+        }
+        Some(((total - idle.min(total)) as f64 / total as f64 * 100.0) as f32)
+    }
+
+    /// Hottest reading across `/sys/class/thermal/thermal_zone*`
+    fn temp_celsius(&self) -> Option<f32> {
+        let zones = fs::read_dir(self.root.join("sys/class/thermal")).ok()?;
+
+        zones
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("thermal_zone")
+            })
+            .filter_map(|entry| fs::read_to_string(entry.path().join("temp")).ok())
+            .filter_map(|millidegrees| millidegrees.trim().parse::<i64>().ok())
+            .map(|millidegrees| millidegrees as f32 / 1000.0)
+            .reduce(f32::max)
+    }
+}
+
+fn parse_cpu_times(stat: &str) -> Option<CpuTimes> {
+    let line = stat.lines().find(|line| line.starts_with("cpu "))?;
+    let fields: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .filter_map(|field| field.parse().ok())
+        .collect();
+    if fields.len() < 4 {
+        return None;
+    }
+
+    // user nice system idle iowait irq softirq steal; guest time is already part of user
+    let total = fields.iter().take(8).sum();
+    let idle = fields[3] + fields.get(4).copied().unwrap_or(0);
+    Some(CpuTimes { idle, total })
+}
+
+/// The /proc/meminfo fields we care about, in bytes
+#[derive(Debug, Default)]
+struct MemInfo {
+    mem_total: Option<u64>,
+    mem_available: Option<u64>,
+    mem_free: Option<u64>,
+    buffers: Option<u64>,
+    cached: Option<u64>,
+    swap_total: Option<u64>,
+    swap_free: Option<u64>,
+}
+
+impl MemInfo {
+    fn memory(&self) -> Option<Usage> {
+        let total = self.mem_total?;
+        // Kernels before 3.14 have no MemAvailable
+        let available = self.mem_available.or_else(|| {
+            Some(self.mem_free? + self.buffers.unwrap_or(0) + self.cached.unwrap_or(0))
+        })?;
+        Some(Usage {
+            used_bytes: total.saturating_sub(available),
+            total_bytes: total,
+        })
+    }
+
+    fn swap(&self) -> Option<Usage> {
+        let total = self.swap_total?;
+        Some(Usage {
+            used_bytes: total.saturating_sub(self.swap_free?),
+            total_bytes: total,
+        })
+    }
+}
+
+fn parse_meminfo(meminfo: &str) -> MemInfo {
+    let mut info = MemInfo::default();
+
+    for line in meminfo.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let mut parts = value.split_whitespace();
+        let Some(amount) = parts.next().and_then(|v| v.parse::<u64>().ok()) else {
+            continue;
+        };
+        let bytes = match parts.next() {
+            Some("kB") => amount * 1024,
+            _ => amount,
+        };
+
+        let field = match key {
+            "MemTotal" => &mut info.mem_total,
+            "MemAvailable" => &mut info.mem_available,
+            "MemFree" => &mut info.mem_free,
+            "Buffers" => &mut info.buffers,
+            "Cached" => &mut info.cached,
+            "SwapTotal" => &mut info.swap_total,
+            "SwapFree" => &mut info.swap_free,
+            _ => continue,
+        };
+        *field = Some(bytes);
+    }
+
+    info
+}
+
+fn parse_uptime(uptime: &str) -> Option<u64> {
+    let seconds: f64 = uptime.split_whitespace().next()?.parse().ok()?;
+    Some(seconds as u64)
+}
+
+/// Usage of the filesystem containing `path`, the same numbers `df` reports
+// The statvfs field widths differ between 32 and 64 bit targets
+#[allow(clippy::useless_conversion)]
+pub fn disk_usage(path: &Path) -> Option<Usage> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    // SAFETY: `c_path` is NUL-terminated and `stat` is a valid, writable statvfs
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+
+    let fragment = u64::from(stat.f_frsize);
+    let used = u64::from(stat.f_blocks).saturating_sub(u64::from(stat.f_bfree)) * fragment;
+    let available = u64::from(stat.f_bavail) * fragment;
+    Some(Usage {
+        used_bytes: used,
+        total_bytes: used + available,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const STAT: &str = "cpu  100 0 100 800 0 0 0 0 0 0\ncpu0 100 0 100 800 0 0 0 0 0 0\n";
+    const MEMINFO: &str = "MemTotal:        1000 kB\nMemFree:          100 kB\n\
+        MemAvailable:     400 kB\nBuffers:           50 kB\nCached:           50 kB\n\
+        SwapTotal:        200 kB\nSwapFree:         150 kB\n";
+
+    fn write(root: &Path, path: &str, contents: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn fixture() -> TempDir {
+        let root = tempfile::tempdir().unwrap();
+        write(root.path(), "proc/stat", STAT);
+        write(root.path(), "proc/meminfo", MEMINFO);
+        write(root.path(), "proc/uptime", "12345.67 54321.00\n");
+        write(
+            root.path(),
+            "sys/class/thermal/thermal_zone0/temp",
+            "41000\n",
+        );
+        write(
+            root.path(),
+            "sys/class/thermal/thermal_zone1/temp",
+            "52500\n",
+        );
+        write(
+            root.path(),
+            "sys/class/thermal/cooling_device0/temp",
+            "99000\n",
+        );
+        root
+    }
+
+    #[test]
+    fn parses_fixture_files() {
+        let root = fixture();
+        let stats = SystemCollector::new(root.path()).collect();
+
+        assert_eq!(stats.temp_celsius, Some(52.5));
+        assert_eq!(
+            stats.memory,
+            Some(Usage {
+                used_bytes: 600 * 1024,
+                total_bytes: 1000 * 1024
+            })
+        );
+        assert_eq!(
+            stats.swap,
+            Some(Usage {
+                used_bytes: 50 * 1024,
+                total_bytes: 200 * 1024
+            })
+        );
+        assert_eq!(stats.uptime_secs, Some(12345));
+        assert!(stats.disk.is_some());
+    }
+
+    #[test]
+    fn memory_without_mem_available_counts_buffers_and_cache() {
+        let info =
+            parse_meminfo("MemTotal: 1000 kB\nMemFree: 100 kB\nBuffers: 50 kB\nCached: 250 kB\n");
+        assert_eq!(info.memory().unwrap().used_bytes, 600 * 1024);
+        assert_eq!(info.swap(), None);
+    }
+
+    #[test]
+    fn cpu_percent_is_the_delta_between_samples() {
+        let root = fixture();
+        let mut collector = SystemCollector::new(root.path());
+
+        // Unchanged counters give no delta to measure
+        assert_eq!(collector.collect().cpu_percent, None);
+
+        // 100 more busy and 300 more idle jiffies
+        write(
+            root.path(),
+            "proc/stat",
+            "cpu  150 0 150 1000 100 0 0 0 0 0\n",
+        );
+        assert_eq!(collector.collect().cpu_percent, Some(25.0));
+
+        write(
+            root.path(),
+            "proc/stat",
+            "cpu  250 0 250 1000 100 0 0 0 0 0\n",
+        );
+        assert_eq!(collector.collect().cpu_percent, Some(100.0));
+    }
+
+    #[test]
+    fn counters_going_backwards_give_no_reading() {
+        let root = fixture();
+        let mut collector = SystemCollector::new(root.path());
+
+        write(root.path(), "proc/stat", "cpu  10 0 10 80 0 0 0 0 0 0\n");
+        assert_eq!(collector.collect().cpu_percent, None);
+    }
+
+    #[test]
+    fn missing_files_give_none() {
+        let root = tempfile::tempdir().unwrap();
+        let stats = SystemCollector::new(root.path()).collect();
+
+        assert_eq!(stats.temp_celsius, None);
+        assert_eq!(stats.cpu_percent, None);
+        assert_eq!(stats.memory, None);
+        assert_eq!(stats.swap, None);
+        assert_eq!(stats.uptime_secs, None);
+    }
+
+    #[test]
+    fn garbled_files_give_none() {
+        let root = tempfile::tempdir().unwrap();
+        write(root.path(), "proc/stat", "cpu  not numbers at all\n");
+        write(
+            root.path(),
+            "proc/meminfo",
+            "MemTotal 1000 kB\nSwapTotal: lots\n",
+        );
+        write(root.path(), "proc/uptime", "soon\n");
+        write(root.path(), "sys/class/thermal/thermal_zone0/temp", "hot\n");
+
+        let mut collector = SystemCollector::new(root.path());
+        write(root.path(), "proc/stat", "cpu  1 2\n");
+        let stats = collector.collect();
+
+        assert_eq!(stats.temp_celsius, None);
+        assert_eq!(stats.cpu_percent, None);
+        assert_eq!(stats.memory, None);
+        assert_eq!(stats.swap, None);
+        assert_eq!(stats.uptime_secs, None);
+    }
+}
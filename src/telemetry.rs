@@ -0,0 +1,83 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Process-wide event counters, exported by the Prometheus endpoint
+pub struct Counters {
+    pub api_success: AtomicU64,
+    pub api_failure: AtomicU64,
+    pub downloads: AtomicU64,
+    pub schedule_switches: AtomicU64,
+    pub mpv_restarts: AtomicU64,
+}
+
+pub static COUNTERS: Counters = Counters {
+    api_success: AtomicU64::new(0),
+    api_failure: AtomicU64::new(0),
+    downloads: AtomicU64::new(0),
+    schedule_switches: AtomicU64::new(0),
+    mpv_restarts: AtomicU64::new(0),
+};
+
+/// Increments one of the `COUNTERS`
+pub fn count(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn read(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+/// Where the schedule being played came from
+#[derive(Debug, Clone, Copy)]
+pub struct ScheduleSource {
+    /// Last time the backend confirmed the schedule, or when the cached copy was fetched
+    pub synced_at: DateTime<Utc>,
+    /// True while running from the on-disk copy because the backend is unreachable
+    pub cached: bool,
+}
+
+static SCHEDULE_SOURCE: Mutex<Option<ScheduleSource>> = Mutex::new(None);
+
+pub fn set_schedule_source(source: Option<ScheduleSource>) {
+    *SCHEDULE_SOURCE.lock().unwrap_or_else(|e| e.into_inner()) = source;
+}
+
+pub fn schedule_source() -> Option<ScheduleSource> {
+    *SCHEDULE_SOURCE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A transfer in flight, as reported in the metrics payload
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DownloadProgress {
+    pub asset_id: String,
+    pub downloaded_bytes: u64,
+    pub total_bytes: Option<u64>,
+}
+
+static DOWNLOADS: Mutex<BTreeMap<String, DownloadProgress>> = Mutex::new(BTreeMap::new());
+
+pub fn set_download_progress(progress: DownloadProgress) {
+    DOWNLOADS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(progress.asset_id.clone(), progress);
+}
+
+pub fn finish_download(asset_id: &str) {
+    DOWNLOADS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(asset_id);
+}
+
+pub fn download_progress() -> Vec<DownloadProgress> {
+    DOWNLOADS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .values()
+        .cloned()
+        .collect()
+}
@@ -1,8 +1,12 @@
+use crate::cache::MediaCache;
+use crate::downloads::Bandwidth;
+use crate::telemetry::{count, COUNTERS};
 use anyhow::Result;
-use chrono::{DateTime, Utc};
 use futures_util::StreamExt;
-use reqwest::Client;
+use reqwest::header::{HeaderMap, CONTENT_RANGE, ETAG, IF_RANGE, RANGE};
+use reqwest::{Client, StatusCode};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{boxed::Box, error::Error, path::Path};
 use tokio::process::Command;
 use tokio::{
@@ -12,11 +16,6 @@ use tokio::{
 
 use std::env;
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Apikey {
-    pub key: String,
-}
-
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Video {
     pub id: String,
@@ -25,11 +24,12 @@ pub struct Video {
     pub asset_order: u8,
     #[serde(default)]
     pub asset_name: String,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Updated {
-    pub updated: Option<DateTime<Utc>>,
+    /// Size in bytes, when the backend knows it
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// Hex SHA-256 of the file, when the backend knows it
+    #[serde(default)]
+    pub sha256: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -44,7 +44,7 @@ pub struct ClientTimelineScheduleResponse {
     pub rotation: Option<i32>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct ClientUpdateFlagsResponse {
     pub playlist_update_needed: bool,
     pub schedule_update_needed: bool,
@@ -54,58 +54,230 @@ pub struct ClientUpdateFlagsResponse {
     pub current_rotation: Option<i32>,
 }
 
+/// Resume state kept next to a `.part` file
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct PartialDownload {
+    /// Validator of the response the part came from, sent back as `If-Range`
+    etag: Option<String>,
+    /// Full size of the file as announced by the server
+    length: Option<u64>,
+}
+
+/// Total size from a `Content-Range: bytes a-b/total` header
+fn content_range_total(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .rsplit('/')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Lowercase hex SHA-256 of the file at `path`
+pub async fn sha256_file(path: &str) -> Result<String, Box<dyn Error>> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
 impl Video {
-    /// Downloads videos or images to `$HOME/.local/share/signage`
-    pub async fn download(&self, client: &Client) -> Result<String, Box<dyn std::error::Error>> {
+    /// Where the asset lives once downloaded: `$HOME/.local/share/signage/{id}.{ext}`
+    pub fn file_path(&self) -> Result<String, Box<dyn Error>> {
         // Extract the file extension from the URL
         let path = Path::new(&self.asset_url);
         let extension = path
             .extension()
             .and_then(std::ffi::OsStr::to_str)
             .unwrap_or("bin");
-        // Clean up the directory after a successful download
-
-        let file_path = format!(
-            "{}/.local/share/signage/{}.{}",
-            std::env::var("HOME")?,
-            self.id,
-            extension
-        );
-
-        // Check if the file already exists
-        if Path::new(&file_path).exists() {
-            println!("File already exists: {}", file_path);
-            return Ok(file_path);
+
+        Ok(format!("{}/{}.{}", data_dir()?, self.id, extension))
+    }
+
+    /// Checks the file at `path` against the size and SHA-256 the backend gave for this asset,
+    /// and against `length` when the backend gave no size. Assets with none of these pass.
+    pub async fn verify(&self, path: &str, length: Option<u64>) -> Result<(), Box<dyn Error>> {
+        let actual = fs::metadata(path).await?.len();
+        if let Some(expected) = self.size.or(length) {
+            if actual != expected {
+                return Err(format!("expected {} bytes, found {}", expected, actual).into());
+            }
         }
 
-        // Proceed with downloading the file
-        let mut stream = client.get(&self.asset_url).send().await?.bytes_stream();
-        let mut file = File::create(&file_path).await?;
+        if let Some(expected) = &self.sha256 {
+            let actual = sha256_file(path).await?;
+            if !actual.eq_ignore_ascii_case(expected) {
+                return Err(format!("expected SHA-256 {}, found {}", expected, actual).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Downloads videos or images to `$HOME/.local/share/signage`.
+    ///
+    /// Data goes to `{path}.part` first and an interrupted download resumes from where it
+    /// stopped, provided the server still has the same version (checked with `If-Range`). The
+    /// file is verified, synced to disk and only then renamed into place, so a file at the
+    /// final path is always complete.
+    ///
+    /// Room is reserved in `cache` once the size is known, from the backend or the response,
+    /// and again for the finished file before the rename, so an asset of unknown size can't
+    /// slip past the quota.
+    pub async fn download(
+        &self,
+        client: &Client,
+        bandwidth: &Bandwidth,
+        cache: &MediaCache,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let file_path = self.file_path()?;
 
-        while let Some(content) = stream.next().await {
-            tokio::io::copy(&mut content?.as_ref(), &mut file).await?;
+        // Check if the file already exists, and that it isn't cut short
+        if let Ok(metadata) = fs::metadata(&file_path).await {
+            match self.size {
+                Some(expected) if metadata.len() != expected => {
+                    eprintln!(
+                        "Replacing {}: expected {} bytes, found {}",
+                        file_path,
+                        expected,
+                        metadata.len()
+                    );
+                    fs::remove_file(&file_path).await?;
+                }
+                _ => {
+                    println!("File already exists: {}", file_path);
+                    return Ok(file_path);
+                }
+            }
         }
 
-        println!("Downloaded to: {}", file_path);
+        let part_path = format!("{}.part", file_path);
+        let meta_path = format!("{}.part.json", file_path);
+        let resume_from = match fs::metadata(&part_path).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+        let mut partial: PartialDownload = match fs::read(&meta_path).await {
+            Ok(contents) if resume_from > 0 => {
+                serde_json::from_slice(&contents).unwrap_or_default()
+            }
+            _ => PartialDownload::default(),
+        };
 
-        Ok(file_path)
-    }
+        let mut request = client.get(&self.asset_url);
+        if resume_from > 0 {
+            request = request.header(RANGE, format!("bytes={}-", resume_from));
+            if let Some(etag) = &partial.etag {
+                request = request.header(IF_RANGE, etag);
+            }
+        }
+        let response = request.send().await?;
 
-    pub fn in_whitelist(&self) -> bool {
-        let whitelist = ["s3.amazonaws.com"];
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => {
+                println!("Resuming {} from byte {}", file_path, resume_from);
+                partial.length = content_range_total(response.headers()).or(partial.length);
+            }
+            // The part already holds everything the server has
+            StatusCode::RANGE_NOT_SATISFIABLE => (),
+            status if status.is_success() => {
+                partial = PartialDownload {
+                    etag: response
+                        .headers()
+                        .get(ETAG)
+                        .and_then(|etag| etag.to_str().ok())
+                        .map(str::to_string),
+                    length: response.content_length(),
+                };
+            }
+            status => {
+                return Err(format!("Failed to download {}: {}", self.asset_url, status).into())
+            }
+        }
 
-        for url in whitelist {
-            if self.asset_url.contains(url) {
-                return true;
-            } else {
-                println!("URL not in whitelist: {}", self.asset_url);
+        if response.status() != StatusCode::RANGE_NOT_SATISFIABLE {
+            // A full response replaces whatever was there
+            let resumed = response.status() == StatusCode::PARTIAL_CONTENT;
+            if let Some(total) = self.size.or(partial.length) {
+                let written = if resumed { resume_from } else { 0 };
+                cache
+                    .reserve(&self.id, total, total.saturating_sub(written))
+                    .await?;
             }
+
+            fs::write(&meta_path, serde_json::to_vec(&partial)?).await?;
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(resumed)
+                .truncate(!resumed)
+                .open(&part_path)
+                .await?;
+
+            let mut downloaded = if resumed { resume_from } else { 0 };
+            let mut stream = response.bytes_stream();
+            while let Some(content) = stream.next().await {
+                let content = content?;
+                file.write_all(&content).await?;
+                downloaded += content.len() as u64;
+                bandwidth
+                    .received(&self.id, content.len(), downloaded, partial.length)
+                    .await;
+            }
+            file.sync_all().await?;
+        }
+
+        let verified = self
+            .verify(&part_path, partial.length)
+            .await
+            .map_err(|e| e.to_string());
+        if let Err(e) = verified {
+            // Start over next time rather than resuming onto bad data
+            let _ = fs::remove_file(&part_path).await;
+            let _ = fs::remove_file(&meta_path).await;
+            return Err(
+                format!("Download of {} failed verification: {}", self.asset_url, e).into(),
+            );
         }
 
-        false
+        // The file is on disk already, so only the quota can still refuse it
+        let size = fs::metadata(&part_path).await?.len();
+        let fits = cache
+            .reserve(&self.id, size, 0)
+            .await
+            .map_err(|e| e.to_string());
+        if let Err(e) = fits {
+            let _ = fs::remove_file(&part_path).await;
+            let _ = fs::remove_file(&meta_path).await;
+            return Err(format!("Download of {} does not fit: {}", self.asset_url, e).into());
+        }
+
+        fs::rename(&part_path, &file_path).await?;
+        let _ = fs::remove_file(&meta_path).await;
+        // Make the rename itself durable
+        let dir = data_dir()?;
+        File::open(&dir).await?.sync_all().await?;
+
+        println!("Downloaded to: {}", file_path);
+        count(&COUNTERS.downloads);
+
+        Ok(file_path)
     }
 }
 
+/// `$HOME/.local/share/signage`, where data, media and buffered records live
+pub fn data_dir() -> Result<String, Box<dyn Error>> {
+    Ok(format!("{}/.local/share/signage", env::var("HOME")?))
+}
+
 /// Loads json from `dir/filename` into `T`
 pub async fn load_json<T: Serialize + DeserializeOwned>(
     json: &mut T,
@@ -134,47 +306,78 @@ pub async fn run_command(
     Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
 
-/// Writes json from `T` into `path`
+/// Writes json from `T` into `path`.
+///
+/// The json goes to `{path}.tmp` and is synced before it replaces `path`, so a crash or power
+/// cut leaves either the old file or the new one, never half of one.
 pub async fn write_json<T: Serialize>(json: &T, path: &str) -> Result<(), Box<dyn Error>> {
-    let mut file = File::create(path).await?;
+    let tmp = format!("{}.tmp", path);
+    let mut file = File::create(&tmp).await?;
     file.write_all(&serde_json::to_vec_pretty(&json)?).await?;
+    file.sync_all().await?;
+    fs::rename(&tmp, path).await?;
 
     Ok(())
 }
 
-/// Cleans up the signage directory by removing files not listed in playlist.txt
-pub async fn cleanup_directory(dir: &str, _videos: &[Video]) -> Result<(), Box<dyn Error>> {
-    // Read the playlist.txt file
-    let playlist_path = format!("{}/playlist.txt", dir);
-    let mut playlist_file = File::open(&playlist_path).await?;
-    let mut playlist_contents = String::new();
-    playlist_file.read_to_string(&mut playlist_contents).await?;
-
-    // Collect all filenames listed in playlist.txt
-    let playlist_files: Vec<String> = playlist_contents
-        .lines()
-        .map(|line| line.trim().to_string())
-        .collect();
-
-    // Read the directory contents
+/// Files the daemon itself keeps in the signage directory
+const STATE_FILES: [&str; 8] = [
+    "data.json",
+    "playlist.txt",
+    "metrics.json",
+    "metrics_buffer.jsonl",
+    "schedule_cache.json",
+    "media_index.json",
+    "proof_of_play.jsonl",
+    "pending_command.json",
+];
+
+/// Names of the media files in the signage directory: everything but daemon state, unfinished
+/// downloads and temporary files
+pub async fn media_files(dir: impl AsRef<Path>) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut files = Vec::new();
     let mut dir_entries = fs::read_dir(dir).await?;
 
     while let Some(entry) = dir_entries.next_entry().await? {
         let path = entry.path();
-        if path.is_file() {
-            let filename = path.file_name().unwrap().to_string_lossy().to_string();
-            // Ignore playlist.txt and data.json
-            println!("Getting Files: {:?}", filename);
-            if filename != "playlist.txt" && filename != "data.json" {
-                // Delete the file if it's not in playlist.txt
-                if !playlist_files.iter().any(|f| f.contains(&filename)) {
-                    println!("Deleting file: {}", filename);
-                    fs::remove_file(path).await?;
-                }
-            }
+        if !path.is_file() {
+            continue;
+        }
+        let Some(filename) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+
+        let in_progress = [".part", ".part.json", ".tmp"]
+            .iter()
+            .any(|suffix| filename.ends_with(suffix));
+        if !STATE_FILES.contains(&filename) && !in_progress {
+            files.push(filename.to_string());
         }
     }
-    Ok(())
+    Ok(files)
+}
+
+/// Restarts signaged.service, taking this process and the player down with it
+pub async fn restart_service() -> Result<(), Box<dyn Error>> {
+    let restart_service_output = Command::new("sudo")
+        .arg("systemctl")
+        .arg("restart")
+        .arg("signaged.service")
+        .output()
+        .await;
+
+    match restart_service_output {
+        Ok(output) if output.status.success() => {
+            println!("Signage service restarted successfully.");
+            Ok(())
+        }
+        Ok(output) => Err(format!(
+            "Failed to restart signage service: {}",
+            String::from_utf8_lossy(&output.stderr)
+        )
+        .into()),
+        Err(e) => Err(format!("Failed to execute restart command: {}", e).into()),
+    }
 }
 
 pub fn set_display() {
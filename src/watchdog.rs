@@ -0,0 +1,296 @@
+use crate::api::SignageApi;
+use crate::config::Config;
+use crate::content::playlist_path;
+use crate::player::{Player, PlayerConfig};
+use crate::telemetry::{count, COUNTERS};
+use crate::util::restart_service;
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde::Serialize;
+use tokio::sync::watch;
+use tokio::time::{self, Duration, Instant, MissedTickBehavior};
+
+/// What went wrong with the player
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    /// The mpv process exited
+    Exited,
+    /// mpv stopped answering on its IPC socket
+    Unresponsive,
+    /// mpv answers, but the position in the file stopped moving
+    Stalled,
+}
+
+/// What the watchdog did about a fault
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Recovery {
+    Restarted,
+    RestartFailed,
+    ServiceRestart,
+}
+
+/// One player failure, as reported to the backend
+#[derive(Debug, Serialize)]
+pub struct PlayerIncident {
+    pub fault: Fault,
+    pub recovery: Recovery,
+    /// Restarts in a row, including this one, that have not yet brought playback back
+    pub attempt: u32,
+    /// The file that was playing, if it was known
+    pub file: Option<String>,
+    pub error: Option<String>,
+    pub occurred_at: DateTime<Utc>,
+}
+
+enum Health {
+    /// Playing and moving, paused on purpose, or idle with nothing to play
+    Healthy,
+    /// Nothing is wrong yet, but playback hasn't been seen moving either
+    Pending,
+    Faulty(Fault),
+}
+
+/// What the previous checks saw
+struct Monitor {
+    stall_timeout: Duration,
+    /// File and position last seen, and when they last changed
+    progress: Option<(String, f64, Instant)>,
+    unresponsive_since: Option<Instant>,
+}
+
+impl Monitor {
+    fn new(config: &PlayerConfig) -> Self {
+        Monitor {
+            stall_timeout: Duration::from_secs(config.stall_timeout_secs),
+            progress: None,
+            unresponsive_since: None,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.progress = None;
+        self.unresponsive_since = None;
+    }
+
+    /// The file last seen playing
+    fn file(&self) -> Option<String> {
+        self.progress.as_ref().map(|(file, _, _)| file.clone())
+    }
+
+    async fn check(&mut self, player: &Player) -> Health {
+        if player.has_exited().await {
+            return Health::Faulty(Fault::Exited);
+        }
+
+        let status = match player.ipc().status().await {
+            Ok(status) => status,
+            Err(_) => {
+                let since = *self.unresponsive_since.get_or_insert_with(Instant::now);
+                return if since.elapsed() >= self.stall_timeout {
+                    Health::Faulty(Fault::Unresponsive)
+                } else {
+                    Health::Pending
+                };
+            }
+        };
+        self.unresponsive_since = None;
+
+        let (Some(file), Some(position)) = (status.path, status.position) else {
+            self.progress = None;
+            return Health::Healthy;
+        };
+        if status.paused {
+            self.progress = None;
+            return Health::Healthy;
+        }
+
+        match &self.progress {
+            Some((last_file, last_position, since))
+                if *last_file == file && (position - last_position).abs() < 0.01 =>
+            {
+                if since.elapsed() >= self.stall_timeout {
+                    Health::Faulty(Fault::Stalled)
+                } else {
+                    Health::Pending
+                }
+            }
+            Some(_) => {
+                self.progress = Some((file, position, Instant::now()));
+                Health::Healthy
+            }
+            None => {
+                self.progress = Some((file, position, Instant::now()));
+                Health::Pending
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    RestartPlayer,
+    RestartService,
+}
+
+/// Counts the restarts in a row that have not brought playback back
+struct Restarts {
+    max_restarts: u32,
+    failures: u32,
+}
+
+impl Restarts {
+    fn new(max_restarts: u32) -> Self {
+        Restarts {
+            max_restarts,
+            failures: 0,
+        }
+    }
+
+    /// Playback is moving again
+    fn recovered(&mut self) {
+        self.failures = 0;
+    }
+
+    /// What to do about another fault, and which attempt in a row it is. The count starts
+    /// over after a service restart, so one that fails is retried after another round of
+    /// player restarts and their backoff rather than on every check.
+    fn next(&mut self) -> (Action, u32) {
+        self.failures += 1;
+        let attempt = self.failures;
+        if attempt > self.max_restarts {
+            self.failures = 0;
+            (Action::RestartService, attempt)
+        } else {
+            (Action::RestartPlayer, attempt)
+        }
+    }
+}
+
+async fn report(api: &SignageApi, incident: PlayerIncident) {
+    eprintln!(
+        "Player {:?}: {:?} (attempt {}){}",
+        incident.fault,
+        incident.recovery,
+        incident.attempt,
+        incident
+            .error
+            .as_deref()
+            .map(|e| format!(": {}", e))
+            .unwrap_or_default()
+    );
+    if let Err(e) = api.report_player_incident(&incident).await {
+        eprintln!("Failed to report player incident: {}", e);
+    }
+}
+
+/// Watches the player the daemon launched and brings it back when it exits, stops answering
+/// on its IPC socket, or freezes.
+///
+/// Restarts back off per the retry policy. Once `max_restarts` in a row have failed to get
+/// playback moving again, the whole service is restarted instead.
+pub async fn run(client: Client, config: watch::Receiver<Config>, player: Player) {
+    let (settings, retry) = {
+        let config = config.borrow();
+        (config.player.clone(), config.retry.clone())
+    };
+    let mut monitor = Monitor::new(&settings);
+    let mut restarts = Restarts::new(settings.max_restarts);
+
+    let mut interval = time::interval(Duration::from_secs(settings.check_interval_secs.max(1)));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+        let fault = match monitor.check(&player).await {
+            Health::Healthy => {
+                restarts.recovered();
+                continue;
+            }
+            Health::Pending => continue,
+            Health::Faulty(fault) => fault,
+        };
+
+        let file = monitor.file();
+        monitor.reset();
+        let (action, attempt) = restarts.next();
+        // Built from the latest config, so a rotated API key is picked up
+        let api = SignageApi::new(client.clone(), &config.borrow());
+
+        if action == Action::RestartService {
+            // Reported first, since a successful restart takes this process down
+            report(
+                &api,
+                PlayerIncident {
+                    fault,
+                    recovery: Recovery::ServiceRestart,
+                    attempt,
+                    file,
+                    error: None,
+                    occurred_at: Utc::now(),
+                },
+            )
+            .await;
+            if let Err(e) = restart_service().await {
+                eprintln!("Failed to restart the service: {}", e);
+            }
+            continue;
+        }
+
+        if attempt > 1 {
+            time::sleep(retry.backoff(attempt - 2)).await;
+        }
+        count(&COUNTERS.mpv_restarts);
+        let playlist = playlist_path().map_err(|e| e.to_string());
+        let result = match playlist {
+            Ok(playlist) => player.restart(&playlist).await.map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        };
+        report(
+            &api,
+            PlayerIncident {
+                fault,
+                recovery: if result.is_ok() {
+                    Recovery::Restarted
+                } else {
+                    Recovery::RestartFailed
+                },
+                attempt,
+                file,
+                error: result.err(),
+                occurred_at: Utc::now(),
+            },
+        )
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_failed_service_restart_starts_the_count_over() {
+        let mut restarts = Restarts::new(2);
+        let player = |attempt| (Action::RestartPlayer, attempt);
+        let service = |attempt| (Action::RestartService, attempt);
+
+        let expected = [
+            player(1),
+            player(2),
+            service(3),
+            player(1),
+            player(2),
+            service(3),
+        ];
+        for step in expected {
+            assert_eq!(restarts.next(), step);
+        }
+
+        // Playback coming back resets it too
+        restarts.next();
+        restarts.recovered();
+        assert_eq!(restarts.next(), player(1));
+    }
+}
@@ -0,0 +1,284 @@
+use crate::commands::Command;
+use crate::config::Config;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::{boxed::Box, error::Error};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{self, Duration, Instant};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Settings under `websocket` in signage.json
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    pub enabled: bool,
+    /// Appended to the API url, followed by `/{id}`
+    pub path: String,
+    /// How often we ping; the connection is dropped after two intervals of silence
+    pub heartbeat_secs: u64,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            enabled: false,
+            path: "/ws/client".to_string(),
+            heartbeat_secs: 30,
+        }
+    }
+}
+
+/// `{url}{path}/{id}` with the http(s) scheme swapped for ws(s)
+fn socket_url(config: &Config) -> String {
+    let url = config.url.trim_end_matches('/');
+    let url = if let Some(rest) = url.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else {
+        url.to_string()
+    };
+    format!("{}{}/{}", url, config.websocket.path, config.id)
+}
+
+/// Keeps a command socket open for the lifetime of the daemon.
+///
+/// Commands are forwarded to `commands`. `connected` is true only while the socket is up, so the
+/// main loop knows when it has to fall back to polling. Reconnects back off per the retry policy
+/// and use the latest `config`, so a rotated API key is picked up.
+pub async fn run(
+    config: watch::Receiver<Config>,
+    commands: mpsc::Sender<Command>,
+    connected: Arc<AtomicBool>,
+) {
+    let mut attempt = 0;
+    loop {
+        let current = config.borrow().clone();
+        match session(&current, &commands, &connected, &mut attempt).await {
+            Ok(()) => println!("Command socket closed"),
+            Err(e) => eprintln!("Command socket error: {}", e),
+        }
+        connected.store(false, Ordering::Relaxed);
+
+        if commands.is_closed() {
+            return;
+        }
+
+        let delay = current.retry.backoff(attempt);
+        println!("Reconnecting command socket in {:?}", delay);
+        time::sleep(delay).await;
+        attempt = attempt.saturating_add(1);
+    }
+}
+
+/// One connection, from handshake until the socket closes or goes quiet
+async fn session(
+    config: &Config,
+    commands: &mpsc::Sender<Command>,
+    connected: &AtomicBool,
+    attempt: &mut u32,
+) -> Result<(), Box<dyn Error>> {
+    let mut request = socket_url(config).into_client_request()?;
+    request.headers_mut().insert(
+        "APIKEY",
+        HeaderValue::from_str(config.key.as_deref().unwrap_or_default())?,
+    );
+
+    let (socket, _) = tokio_tungstenite::connect_async(request).await?;
+    let (mut sink, mut stream) = socket.split();
+    println!("Command socket connected");
+    connected.store(true, Ordering::Relaxed);
+    *attempt = 0;
+
+    let heartbeat = Duration::from_secs(config.websocket.heartbeat_secs.max(1));
+    let mut ping = time::interval(heartbeat);
+    let mut last_seen = Instant::now();
+
+    loop {
+        tokio::select! {
+            _ = ping.tick() => {
+                if last_seen.elapsed() > heartbeat * 2 {
+                    return Err("no heartbeat from server".into());
+                }
+                sink.send(Message::Ping(Vec::new())).await?;
+            }
+            message = stream.next() => {
+                let Some(message) = message else {
+                    return Ok(());
+                };
+                last_seen = Instant::now();
+
+                match message? {
+                    Message::Text(text) => match serde_json::from_str::<Command>(&text) {
+                        Ok(command) => {
+                            println!("Received command {} ({:?}) over socket", command.id, command.kind);
+                            if commands.send(command).await.is_err() {
+                                return Ok(());
+                            }
+                        }
+                        Err(e) => eprintln!("Ignoring unknown socket message {}: {}", text, e),
+                    },
+                    Message::Close(_) => return Ok(()),
+                    // Pings are answered by tungstenite itself
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::CommandKind;
+    use crate::retry::RetryPolicy;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+    use tokio_tungstenite::WebSocketStream;
+    use uuid::Uuid;
+
+    const WAIT: Duration = Duration::from_secs(5);
+
+    fn config(port: u16, key: &str) -> Config {
+        Config {
+            url: format!("http://127.0.0.1:{}/", port),
+            id: "device".to_string(),
+            key: Some(key.to_string()),
+            retry: RetryPolicy {
+                max_attempts: 5,
+                base_delay_ms: 10,
+                max_delay_ms: 50,
+            },
+            ..Config::default()
+        }
+    }
+
+    /// Accepts one socket, returning it with the request path and `APIKEY` header
+    // The callback's error type is tungstenite's, not ours
+    #[allow(clippy::result_large_err)]
+    async fn accept(listener: &TcpListener) -> (WebSocketStream<TcpStream>, String, String) {
+        let (stream, _) = time::timeout(WAIT, listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut seen = (String::new(), String::new());
+        let callback = |request: &Request, response: Response| {
+            seen.0 = request.uri().path().to_string();
+            seen.1 = request.headers()["APIKEY"].to_str().unwrap().to_string();
+            Ok(response)
+        };
+        let socket = tokio_tungstenite::accept_hdr_async(stream, callback)
+            .await
+            .unwrap();
+        (socket, seen.0, seen.1)
+    }
+
+    async fn wait_until(condition: impl Fn() -> bool) {
+        time::timeout(WAIT, async {
+            while !condition() {
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn socket_url_swaps_the_scheme() {
+        let mut config = config(80, "key");
+        config.url = "https://api.example.com/".to_string();
+        assert_eq!(
+            socket_url(&config),
+            "wss://api.example.com/ws/client/device"
+        );
+        config.url = "http://api.example.com".to_string();
+        assert_eq!(socket_url(&config), "ws://api.example.com/ws/client/device");
+    }
+
+    #[tokio::test]
+    async fn delivers_commands_while_connected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (_config_tx, config_rx) = watch::channel(config(port, "secret"));
+        let (command_tx, mut command_rx) = mpsc::channel(4);
+        let connected = Arc::new(AtomicBool::new(false));
+        let task = tokio::spawn(run(config_rx, command_tx, connected.clone()));
+
+        let (mut socket, path, key) = accept(&listener).await;
+        assert_eq!(path, "/ws/client/device");
+        assert_eq!(key, "secret");
+        wait_until(|| connected.load(Ordering::Relaxed)).await;
+
+        let id = Uuid::new_v4();
+        socket
+            .send(Message::Text(format!(
+                r#"{{"id":"{}","type":"screenshot"}}"#,
+                id
+            )))
+            .await
+            .unwrap();
+        // Messages that aren't commands are skipped without dropping the socket
+        socket.send(Message::Text("{}".to_string())).await.unwrap();
+        let command = time::timeout(WAIT, command_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(command.id, id);
+        assert_eq!(command.kind, CommandKind::Screenshot);
+
+        socket.close(None).await.unwrap();
+        wait_until(|| !connected.load(Ordering::Relaxed)).await;
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn reconnects_with_the_latest_key_after_the_server_drops() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (config_tx, config_rx) = watch::channel(config(port, "old"));
+        let (command_tx, _command_rx) = mpsc::channel(4);
+        let connected = Arc::new(AtomicBool::new(false));
+        let task = tokio::spawn(run(config_rx, command_tx, connected.clone()));
+
+        let (socket, _, key) = accept(&listener).await;
+        assert_eq!(key, "old");
+        wait_until(|| connected.load(Ordering::Relaxed)).await;
+
+        config_tx.send_replace(config(port, "rotated"));
+        drop(socket);
+        wait_until(|| !connected.load(Ordering::Relaxed)).await;
+
+        let (_socket, _, key) = accept(&listener).await;
+        assert_eq!(key, "rotated");
+        wait_until(|| connected.load(Ordering::Relaxed)).await;
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn keeps_retrying_while_the_server_is_down() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (_config_tx, config_rx) = watch::channel(config(port, "key"));
+        let (command_tx, _command_rx) = mpsc::channel(4);
+        let connected = Arc::new(AtomicBool::new(false));
+        let task = tokio::spawn(run(config_rx, command_tx, connected.clone()));
+
+        // Refuse the handshake a few times by closing the connection straight away
+        for _ in 0..3 {
+            let (stream, _) = time::timeout(WAIT, listener.accept())
+                .await
+                .unwrap()
+                .unwrap();
+            drop(stream);
+            assert!(!connected.load(Ordering::Relaxed));
+        }
+
+        let _socket = accept(&listener).await;
+        wait_until(|| connected.load(Ordering::Relaxed)).await;
+        task.abort();
+    }
+}